@@ -9,6 +9,7 @@
     allow(inconsistent_qualification)
 )]
 
+use crate::coverage_config::CoverageConfig;
 use crate::{get_keypair, is_hidden, keys_sync, DEFAULT_RPC_PORT};
 use anchor_client::Cluster;
 use anchor_lang_idl::types::Idl;
@@ -308,6 +309,8 @@ pub struct Config {
     // not the Test.toml files
     pub test_validator: Option<TestValidator>,
     pub test_config: Option<TestConfig>,
+    // smoelius: `anchor-coverage`'s own settings.
+    pub coverage: Option<CoverageConfig>,
 }
 
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
@@ -524,6 +527,7 @@ struct _Config {
     workspace: Option<WorkspaceConfig>,
     scripts: Option<ScriptsConfig>,
     test: Option<_TestValidator>,
+    coverage: Option<CoverageConfig>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -624,6 +628,7 @@ impl fmt::Display for Config {
             programs,
             workspace: (!self.workspace.members.is_empty() || !self.workspace.exclude.is_empty())
                 .then(|| self.workspace.clone()),
+            coverage: self.coverage.clone(),
         };
 
         let cfg = toml::to_string(&cfg).expect("Must be well formed");
@@ -650,6 +655,7 @@ impl FromStr for Config {
             test_config: None,
             programs: cfg.programs.map_or(Ok(BTreeMap::new()), deser_programs)?,
             workspace: cfg.workspace.unwrap_or_default(),
+            coverage: cfg.coverage,
         })
     }
 }
@@ -60,10 +60,7 @@ pub fn __keys_sync(cfg_override: &ConfigOverride, program_name: Option<String>)
 }
 
 // smoelius: The remaining functions are stand-ins for Anchor functions with the same names.
-
-pub fn cd_member(_cfg_override: &ConfigOverride, _program_name: &str) -> Result<()> {
-    Ok(())
-}
+// `anchor-coverage doctor` checks what `check_overflow` and `check_deps` would.
 
 pub fn check_overflow(_cargo_toml_path: impl AsRef<Path>) -> Result<bool> {
     Ok(false)
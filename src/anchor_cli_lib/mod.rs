@@ -3,7 +3,8 @@
 //
 //     https://github.com/solana-foundation/anchor/blob/v0.32.1/cli/src/lib.rs
 //
-// A small addition has been made to `_build_rust_cwd` to pass `--debug` to `cargo-build-sbf`.
+// Small additions have been made to `_build_rust_cwd` to pass `--debug` to `cargo-build-sbf`, and to
+// pass it `env_vars`.
 //
 // See the following issue for context: https://github.com/solana-foundation/anchor/issues/3643
 
@@ -249,7 +250,7 @@ fn build_rust_cwd(
     };
     match build_config.verifiable {
         false => _build_rust_cwd(
-            cfg, no_idl, idl_out, idl_ts_out, skip_lint, no_docs, arch, cargo_args,
+            cfg, no_idl, idl_out, idl_ts_out, skip_lint, no_docs, arch, cargo_args, env_vars,
         ),
         true => build_cwd_verifiable(
             cfg,
@@ -276,6 +277,7 @@ fn _build_rust_cwd(
     no_docs: bool,
     arch: &ProgramArch,
     cargo_args: Vec<String>,
+    env_vars: Vec<String>,
 ) -> Result<()> {
     let exit = std::process::Command::new("cargo")
         .arg(arch.build_subcommand())
@@ -283,6 +285,9 @@ fn _build_rust_cwd(
         // `cargo-build-sbf` causes it to build with debug symbols.
         .arg("--debug")
         .args(cargo_args.clone())
+        // smoelius: The next call to `envs` does not appear in the original either. The original
+        // uses `env_vars` only for verifiable builds.
+        .envs(env_vars.iter().filter_map(|env_var| env_var.split_once('=')))
         .stdout(Stdio::inherit())
         .stderr(Stdio::inherit())
         .output()
@@ -408,6 +413,31 @@ enum OutFile {
     File(PathBuf),
 }
 
+fn cd_member(cfg_override: &ConfigOverride, program_name: &str) -> Result<()> {
+    // Change directories to the given `program_name`, if given.
+    let cfg = Config::discover(cfg_override)?.expect("Not in workspace.");
+
+    for program in cfg.read_all_programs()? {
+        let cargo_toml = program.path.join("Cargo.toml");
+        if !cargo_toml.exists() {
+            return Err(anyhow!(
+                "Did not find Cargo.toml at the path: {}",
+                program.path.display()
+            ));
+        }
+
+        let manifest = Manifest::from_path(&cargo_toml)?;
+        let pkg_name = manifest.package().name();
+        let lib_name = manifest.lib_name()?;
+        if program_name == pkg_name || program_name == lib_name {
+            std::env::set_current_dir(&program.path)?;
+            return Ok(());
+        }
+    }
+
+    Err(anyhow!("{} is not part of the workspace", program_name,))
+}
+
 fn set_workspace_dir_or_exit() {
     let d = match Config::discover(&ConfigOverride::default()) {
         Err(err) => {
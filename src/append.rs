@@ -0,0 +1,103 @@
+//! Accumulates coverage across several invocations in one trace directory.
+//!
+//! In append mode, each invocation writes its traces to a fresh `run-N` subdirectory of the trace
+//! directory. After each invocation, the lcov files and JSON reports of all runs are merged into
+//! an aggregate lcov file and JSON report at the top of the trace directory.
+
+use crate::{
+    merge::{merge, Format, MergeOptions},
+    report::REPORT_FILENAME,
+    util::files_with_extension,
+};
+use anyhow::{ensure, Result};
+use std::{
+    fs::read_dir,
+    path::{Path, PathBuf},
+};
+
+pub const RUN_DIR_PREFIX: &str = "run-";
+
+/// The aggregate lcov file written to the top of the trace directory
+pub const AGGREGATE_LCOV_FILENAME: &str = "coverage.lcov";
+
+/// Returns the `run-N` subdirectories of `trace_dir`, ordered by `N`
+pub fn run_dirs(trace_dir: &Path) -> Result<Vec<PathBuf>> {
+    if !trace_dir.try_exists()? {
+        return Ok(Vec::new());
+    }
+    let mut run_dirs = Vec::new();
+    for result in read_dir(trace_dir)? {
+        let entry = result?;
+        let path = entry.path();
+        if let Some(n) = run_number(&path)
+            && path.is_dir()
+        {
+            run_dirs.push((n, path));
+        }
+    }
+    run_dirs.sort();
+    Ok(run_dirs.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Returns the path of the next `run-N` subdirectory of `trace_dir`, without creating it
+///
+/// Fails if `trace_dir` holds program counters files at its top level, i.e., if it was written to
+/// without `--append`.
+pub fn next_run_dir(trace_dir: &Path) -> Result<PathBuf> {
+    if trace_dir.try_exists()? {
+        ensure!(
+            files_with_extension(trace_dir, "pcs")?.is_empty(),
+            "trace directory `{}` holds traces not written in append mode; pass `--clean` to \
+             delete them",
+            trace_dir.display()
+        );
+    }
+    let n = run_dirs(trace_dir)?
+        .last()
+        .and_then(|path| run_number(path))
+        .map_or(1, |n| n + 1);
+    Ok(trace_dir.join(format!("{RUN_DIR_PREFIX}{n}")))
+}
+
+/// Merges the lcov files and JSON reports of `trace_dir`'s runs into [`AGGREGATE_LCOV_FILENAME`]
+/// and [`REPORT_FILENAME`] at the top of `trace_dir`
+pub fn aggregate(trace_dir: &Path) -> Result<()> {
+    aggregate_dirs(&run_dirs(trace_dir)?, trace_dir)
+}
+
+/// Merges the lcov files and JSON reports in `dirs` into [`AGGREGATE_LCOV_FILENAME`] and
+/// [`REPORT_FILENAME`] in `output_dir`
+pub fn aggregate_dirs(dirs: &[PathBuf], output_dir: &Path) -> Result<()> {
+    let mut lcov_paths = Vec::new();
+    let mut report_paths = Vec::new();
+    for run_dir in dirs {
+        lcov_paths.extend(files_with_extension(run_dir, "lcov")?);
+        let report_path = run_dir.join(REPORT_FILENAME);
+        if report_path.try_exists()? {
+            report_paths.push(report_path);
+        }
+    }
+    lcov_paths.sort();
+
+    merge(&MergeOptions {
+        inputs: lcov_paths,
+        remaps: Vec::new(),
+        output: output_dir.join(AGGREGATE_LCOV_FILENAME),
+        format: Some(Format::Lcov),
+    })?;
+
+    merge(&MergeOptions {
+        inputs: report_paths,
+        remaps: Vec::new(),
+        output: output_dir.join(REPORT_FILENAME),
+        format: Some(Format::Json),
+    })
+}
+
+fn run_number(path: &Path) -> Option<u32> {
+    path.file_name()?
+        .to_str()?
+        .strip_prefix(RUN_DIR_PREFIX)?
+        .parse()
+        .ok()
+}
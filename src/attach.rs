@@ -0,0 +1,144 @@
+//! Support for collecting coverage from a validator that runs while arbitrary commands are run
+//! against it, rather than one started by `anchor test`.
+//!
+//! The validator's settings are taken from `Anchor.toml`'s `[test]` table, as `anchor test` and
+//! `anchor localnet` would use them. Readiness is detected by connecting to the validator's RPC
+//! port, and shutdown by the port's no longer accepting connections.
+
+use crate::trace_writer::SBF_TRACE_DIR;
+use anyhow::{bail, Result};
+use std::{
+    net::{Ipv4Addr, SocketAddr, TcpStream},
+    path::{Path, PathBuf},
+    process::Command,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The settings needed to start a validator and wait for it
+#[derive(Debug)]
+pub struct ValidatorSettings {
+    pub rpc_port: u16,
+    /// How long to wait for the validator to become ready
+    pub startup_wait: Duration,
+    pub ledger: PathBuf,
+    /// The program ID and path of each program to load at genesis
+    pub programs: Vec<(String, PathBuf)>,
+}
+
+impl ValidatorSettings {
+    /// Returns the validator's RPC URL
+    #[must_use]
+    pub fn rpc_url(&self) -> String {
+        format!("http://127.0.0.1:{}", self.rpc_port)
+    }
+
+    /// Returns a `solana-test-validator` command that uses the settings and writes program
+    /// counters files to `sbf_trace_dir`
+    #[must_use]
+    pub fn solana_test_validator_command(&self, sbf_trace_dir: &Path) -> Command {
+        let mut command = Command::new("solana-test-validator");
+        command.env(SBF_TRACE_DIR, sbf_trace_dir);
+        command.arg("--reset");
+        command.arg("--ledger").arg(&self.ledger);
+        command.args(["--rpc-port", &self.rpc_port.to_string()]);
+        for (program_id, path) in &self.programs {
+            command.arg("--bpf-program").arg(program_id).arg(path);
+        }
+        command
+    }
+}
+
+/// Reads the validator settings from `Anchor.toml` in `root`
+///
+/// The programs are those in `[programs.localnet]`, loaded from `target/coverage/deploy`, to which
+/// coverage builds write them, followed by those in `[[test.genesis]]`.
+#[cfg(feature = "__anchor_cli")]
+pub fn validator_settings(root: &Path) -> Result<ValidatorSettings> {
+    use crate::{validator_log::program_ids, DEFAULT_RPC_PORT};
+    use anyhow::Context;
+
+    let anchor_toml = root.join("Anchor.toml");
+    let contents = std::fs::read_to_string(&anchor_toml)?;
+    let config = contents
+        .parse::<crate::config::Config>()
+        .with_context(|| format!("failed to parse `{}`", anchor_toml.display()))?;
+    let startup_wait = config
+        .test_validator
+        .as_ref()
+        .map_or(crate::config::STARTUP_WAIT, |test_validator| {
+            test_validator.startup_wait
+        });
+    let (genesis, validator) = config
+        .test_validator
+        .map(|test_validator| (test_validator.genesis, test_validator.validator))
+        .unwrap_or_default();
+
+    let mut programs = program_ids(&anchor_toml)?
+        .into_iter()
+        .map(|(name, program_id)| {
+            let path = root
+                .join(crate::build::COVERAGE_DEPLOY_DIR)
+                .join(name)
+                .with_extension("so");
+            (program_id, path)
+        })
+        .collect::<Vec<_>>();
+    programs.extend(
+        genesis
+            .into_iter()
+            .flatten()
+            .map(|entry| (entry.address, root.join(entry.program))),
+    );
+
+    Ok(ValidatorSettings {
+        rpc_port: validator
+            .as_ref()
+            .map_or(DEFAULT_RPC_PORT, |validator| validator.rpc_port),
+        startup_wait: Duration::from_millis(u64::try_from(startup_wait).unwrap_or_default()),
+        ledger: root.join(
+            validator.map_or_else(crate::config::get_default_ledger_path, |validator| {
+                PathBuf::from(validator.ledger)
+            }),
+        ),
+        programs,
+    })
+}
+
+#[cfg(not(feature = "__anchor_cli"))]
+pub fn validator_settings(_root: &Path) -> Result<ValidatorSettings> {
+    bail!("reading validator settings requires the `__anchor_cli` feature")
+}
+
+/// Waits until something accepts connections on `rpc_port`, or until `timeout` elapses
+pub fn wait_until_ready(rpc_port: u16, timeout: Option<Duration>) -> Result<()> {
+    let start = Instant::now();
+    while !is_listening(rpc_port) {
+        if let Some(timeout) = timeout
+            && start.elapsed() >= timeout
+        {
+            bail!(
+                "validator did not start listening on port {rpc_port} within {} ms",
+                timeout.as_millis()
+            );
+        }
+        sleep(POLL_INTERVAL);
+    }
+    Ok(())
+}
+
+/// Waits until nothing accepts connections on `rpc_port`
+pub fn wait_until_stopped(rpc_port: u16) {
+    while is_listening(rpc_port) {
+        sleep(POLL_INTERVAL);
+    }
+}
+
+/// Returns true if something accepts connections on `port`
+#[must_use]
+pub fn is_listening(port: u16) -> bool {
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    TcpStream::connect_timeout(&addr, POLL_INTERVAL).is_ok()
+}
@@ -0,0 +1,238 @@
+use crate::{
+    anchor_test_skip_build, build_with_debug, cli::TestArgs, exit_with_test_failure,
+    no_pcs_files_message, validator_log,
+};
+use anchor_coverage::{
+    append::aggregate_dirs,
+    coverage_config::CoverageConfig,
+    deploy::stage_programs,
+    report::{Report, REPORT_FILENAME},
+    test_configs::{
+        comparison_table, discover_test_configs, write_test_toml, ValidatorPorts,
+        ANCHOR_TEST_LOG_FILENAME, COMPARISON_FILENAME, MAX_JOBS,
+    },
+    util::{files_with_extension, StripCurrentDir},
+    validator_log::VALIDATOR_LOG_FILENAME,
+};
+use anyhow::{anyhow, bail, ensure, Result};
+use std::{
+    fs::{create_dir_all, write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+/// The outcome of running one test configuration's tests
+struct Outcome {
+    validator_log: Option<PathBuf>,
+    test_exit_code: Option<i32>,
+}
+
+/// Runs each test configuration, `--jobs` at a time, and writes per-configuration and combined
+/// reports along with a comparison table
+pub fn test_all_configs(
+    test_args: &TestArgs,
+    root: &Path,
+    config: &CoverageConfig,
+    sbf_trace_dir: &Path,
+) -> Result<()> {
+    let jobs = test_args.jobs;
+    let test_configs = discover_test_configs(root, sbf_trace_dir)?;
+    ensure!(
+        !test_configs.is_empty(),
+        "found no `Test.toml` files in: {}",
+        root.display()
+    );
+    ensure!(
+        (1..=MAX_JOBS).contains(&jobs),
+        "the number of jobs must be between 1 and {MAX_JOBS}"
+    );
+    // smoelius: Anchor looks for the generated `Test.toml` files by walking the workspace.
+    ensure!(
+        jobs == 1 || sbf_trace_dir.starts_with(root),
+        "to run test configurations concurrently, the trace directory must be in the workspace: {}",
+        root.display()
+    );
+
+    build_with_debug(root, config)?;
+
+    let dirs = test_configs
+        .iter()
+        .map(|test_config| sbf_trace_dir.join(test_config))
+        .collect::<Vec<_>>();
+
+    let mut reports = Vec::new();
+    let mut failures = Vec::new();
+    let mut process_outcome = |test_config: &Path, dir: &Path, outcome: Outcome| -> Result<()> {
+        let report = process(root, dir, &outcome)?;
+        let mut name = test_config.to_string_lossy().into_owned();
+        if let Some(code) = outcome.test_exit_code {
+            failures.push((name.clone(), code));
+            name.push_str(" (tests failed)");
+        }
+        reports.push((name, report));
+        Ok(())
+    };
+    // smoelius: The coverage `.so` files are staged until the tests finish, and in particular
+    // before `exit_with_test_failure` exits without running destructors.
+    let staged = stage_programs(root)?;
+    if jobs == 1 {
+        for (test_config, dir) in test_configs.iter().zip(&dirs) {
+            eprintln!("Running test config: {}", test_config.display());
+            create_dir_all(dir)?;
+            let mut args = vec![
+                String::from("--run"),
+                test_config.to_string_lossy().into_owned(),
+            ];
+            args.extend(config.anchor_test_args.iter().cloned());
+            let test_exit_code =
+                anchor_test_skip_build(&args, dir, None, test_args.allow_test_failures)?;
+            let outcome = Outcome {
+                validator_log: validator_log(root, config)?,
+                test_exit_code,
+            };
+            process_outcome(test_config, dir, outcome)?;
+        }
+    } else {
+        let outcomes = run_concurrently(test_args, root, config, &test_configs, &dirs)?;
+        for ((test_config, dir), outcome) in test_configs.iter().zip(&dirs).zip(outcomes) {
+            process_outcome(test_config, dir, outcome)?;
+        }
+    }
+    drop(staged);
+
+    aggregate_dirs(&dirs, sbf_trace_dir)?;
+    reports.push((
+        String::from("combined"),
+        Report::read(sbf_trace_dir.join(REPORT_FILENAME))?,
+    ));
+
+    let table = comparison_table(&reports);
+    let comparison_path = sbf_trace_dir.join(COMPARISON_FILENAME);
+    write(&comparison_path, &table)?;
+    eprint!("\n{table}");
+    eprintln!(
+        "\nComparison table written to: {}",
+        comparison_path.strip_current_dir().display()
+    );
+
+    if let Some(&(_, code)) = failures.first() {
+        let names = failures
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        exit_with_test_failure(code, &names);
+    }
+
+    Ok(())
+}
+
+/// Runs `anchor test` for each test configuration, with at most `--jobs` running at once
+///
+/// Each job's validator uses its own ports, and each configuration its own ledger directory. The
+/// output of each `anchor test` is written to a file in the configuration's trace directory.
+fn run_concurrently(
+    test_args: &TestArgs,
+    root: &Path,
+    config: &CoverageConfig,
+    test_configs: &[PathBuf],
+    dirs: &[PathBuf],
+) -> Result<Vec<Outcome>> {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::new());
+
+    thread::scope(|scope| {
+        for job in 0..test_args.jobs {
+            let (next, results) = (&next, &results);
+            scope.spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                let (Some(test_config), Some(dir)) = (test_configs.get(index), dirs.get(index))
+                else {
+                    break;
+                };
+                let result = run_one(test_args, root, config, test_config, dir, job);
+                results.lock().unwrap().push((index, result));
+            });
+        }
+    });
+
+    let mut results = results.into_inner().map_err(|error| anyhow!("{error}"))?;
+    results.sort_by_key(|&(index, _)| index);
+
+    let mut failures = Vec::new();
+    let mut outcomes = Vec::new();
+    for (index, result) in results {
+        match result {
+            Ok(outcome) => outcomes.push(outcome),
+            Err(error) => failures.push(format!("{}: {error}", test_configs[index].display())),
+        }
+    }
+    if !failures.is_empty() {
+        bail!("some test configs failed:\n    {}", failures.join("\n    "));
+    }
+
+    Ok(outcomes)
+}
+
+fn run_one(
+    test_args: &TestArgs,
+    root: &Path,
+    config: &CoverageConfig,
+    test_config: &Path,
+    dir: &Path,
+    job: u16,
+) -> Result<Outcome> {
+    eprintln!("Running test config: {}", test_config.display());
+
+    create_dir_all(dir)?;
+
+    let ledger = root
+        .join(".anchor/coverage")
+        .join(test_config)
+        .join("test-ledger");
+    // smoelius: The generated `Test.toml` is removed when `_guard` is dropped, i.e., after
+    // `anchor test` finishes, whether or not it succeeds.
+    let _guard = write_test_toml(
+        dir,
+        root,
+        test_config,
+        &ValidatorPorts::for_job(job),
+        &ledger,
+    )?;
+
+    let mut args = vec![String::from("--run"), dir.to_string_lossy().into_owned()];
+    args.extend(config.anchor_test_args.iter().cloned());
+    let log_path = dir.join(ANCHOR_TEST_LOG_FILENAME);
+    let test_exit_code =
+        anchor_test_skip_build(&args, dir, Some(&log_path), test_args.allow_test_failures)
+            .map_err(|error| anyhow!("{error}; see: {}", log_path.strip_current_dir().display()))?;
+
+    eprintln!("Finished test config: {}", test_config.display());
+
+    let validator_log = ledger.join(VALIDATOR_LOG_FILENAME);
+    Ok(Outcome {
+        validator_log: validator_log.try_exists()?.then_some(validator_log),
+        test_exit_code,
+    })
+}
+
+fn process(root: &Path, dir: &Path, outcome: &Outcome) -> Result<Report> {
+    if files_with_extension(dir, "pcs")?.is_empty() {
+        bail!(no_pcs_files_message(dir)?);
+    }
+
+    anchor_coverage::run(
+        dir,
+        &anchor_coverage::Options {
+            validator_log: outcome.validator_log.clone(),
+            workspace_root: root.to_path_buf(),
+            test_exit_code: outcome.test_exit_code,
+            ..Default::default()
+        },
+    )?;
+
+    Report::read(dir.join(REPORT_FILENAME))
+}
@@ -0,0 +1,211 @@
+use crate::{
+    build_with_debug, cli::AttachArgs, exit_with_test_failure, no_pcs_files_message,
+    prepare_trace_dir, solana_test_validator_is_patched, use_patched_agave_tools, validator_log,
+    which,
+};
+use anchor_coverage::{
+    attach::{is_listening, validator_settings, wait_until_ready, wait_until_stopped},
+    coverage_config::CoverageConfig,
+    util::files_with_extension,
+    validator_log::VALIDATOR_LOG_FILENAME,
+};
+use anyhow::{bail, ensure, Result};
+use nix::{
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
+use shell_words::quote;
+use std::{
+    fs::{canonicalize, create_dir_all},
+    io::stdin,
+    path::Path,
+    process::{Child, Command, Stdio},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+/// How long a validator is given to exit after being sent `SIGTERM`
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A validator started by `attach`, stopped when dropped
+struct Validator(Child);
+
+impl Drop for Validator {
+    fn drop(&mut self) {
+        // smoelius: `Child::kill` sends `SIGKILL`, which would keep the validator from flushing the
+        // traces it is writing. So send `SIGTERM` first, and kill the validator only if it does
+        // not exit in time.
+        if terminate(&self.0) {
+            let start = Instant::now();
+            while start.elapsed() < STOP_TIMEOUT {
+                if !matches!(self.0.try_wait(), Ok(None)) {
+                    return;
+                }
+                sleep(Duration::from_millis(100));
+            }
+            eprintln!(
+                "Warning: validator did not exit within {} seconds of `SIGTERM`; killing it",
+                STOP_TIMEOUT.as_secs()
+            );
+        }
+        let _: std::io::Result<()> = self.0.kill();
+        let _: std::io::Result<_> = self.0.wait();
+    }
+}
+
+/// Sends `SIGTERM` to `child`, and returns true if the signal was sent
+fn terminate(child: &Child) -> bool {
+    let Ok(pid) = i32::try_from(child.id()) else {
+        eprintln!(
+            "Warning: validator has an invalid process id: {}",
+            child.id()
+        );
+        return false;
+    };
+    match kill(Pid::from_raw(pid), Signal::SIGTERM) {
+        Ok(()) => true,
+        Err(error) => {
+            eprintln!("Warning: failed to send `SIGTERM` to validator: {error}");
+            false
+        }
+    }
+}
+
+pub fn attach(attach_args: &AttachArgs, root: &Path, config: &CoverageConfig) -> Result<()> {
+    let _guard = use_patched_agave_tools(root)?;
+
+    let sbf_trace_dir = config.trace_dir(root);
+
+    prepare_trace_dir(root, &sbf_trace_dir, attach_args.clean, false)?;
+
+    create_dir_all(&sbf_trace_dir)?;
+
+    // smoelius: The validator may run in a different directory, so give it an absolute path.
+    let sbf_trace_dir = canonicalize(&sbf_trace_dir)?;
+
+    build_with_debug(root, config)?;
+
+    let mut settings = validator_settings(root)?;
+    if let Some(rpc_port) = attach_args.rpc_port {
+        settings.rpc_port = rpc_port;
+    }
+
+    let mut command = settings.solana_test_validator_command(&sbf_trace_dir);
+
+    let validator = if attach_args.start_validator {
+        let path = which("solana-test-validator")?;
+        ensure!(
+            solana_test_validator_is_patched(&path)?,
+            "`{}` does not appear to be patched",
+            path.display()
+        );
+        ensure!(
+            !is_listening(settings.rpc_port),
+            "port {} is already in use; is a validator already running?",
+            settings.rpc_port
+        );
+        // smoelius: `solana-test-validator` writes its log to its ledger directory. Its standard
+        // output is a dashboard.
+        command.stdout(Stdio::null());
+        eprintln!("Starting validator: {}", command_line(&command));
+        let validator = Validator(command.spawn()?);
+        wait_until_ready(settings.rpc_port, Some(settings.startup_wait))?;
+        Some(validator)
+    } else {
+        eprintln!(
+            "Start a patched validator that writes program counters files to the trace directory, \
+             e.g.:
+
+    {}
+",
+            command_line(&command)
+        );
+        wait_until_ready(settings.rpc_port, None)?;
+        None
+    };
+
+    eprintln!("Validator is ready at: {}", settings.rpc_url());
+
+    let test_exit_code = if attach_args.command.is_empty() {
+        if validator.is_some() {
+            eprintln!("Press Enter to stop the validator and compute coverage");
+            stdin().read_line(&mut String::new())?;
+        } else {
+            eprintln!("Waiting for the validator to shut down");
+            wait_until_stopped(settings.rpc_port);
+        }
+        None
+    } else {
+        run_command(attach_args, &settings.rpc_url())?
+    };
+
+    drop(validator);
+
+    if files_with_extension(&sbf_trace_dir, "pcs")?.is_empty() {
+        bail!(no_pcs_files_message(&sbf_trace_dir)?);
+    }
+
+    let validator_log = if attach_args.start_validator && config.validator_log.is_none() {
+        let validator_log = settings.ledger.join(VALIDATOR_LOG_FILENAME);
+        validator_log.try_exists()?.then_some(validator_log)
+    } else {
+        validator_log(root, config)?
+    };
+
+    anchor_coverage::run(
+        &sbf_trace_dir,
+        &anchor_coverage::Options {
+            validator_log,
+            workspace_root: root.to_path_buf(),
+            test_exit_code,
+            ..Default::default()
+        },
+    )?;
+
+    if let Some(code) = test_exit_code {
+        exit_with_test_failure(code, &[]);
+    }
+
+    Ok(())
+}
+
+/// Runs the user's command, returning its exit code if it fails and failures are allowed
+fn run_command(attach_args: &AttachArgs, rpc_url: &str) -> Result<Option<i32>> {
+    let (program, args) = attach_args.command.split_first().unwrap();
+    let mut command = Command::new(program);
+    command.args(args);
+    command.env("ANCHOR_PROVIDER_URL", rpc_url);
+    let status = command.status()?;
+    if status.success() {
+        return Ok(None);
+    }
+    ensure!(
+        attach_args.allow_test_failures,
+        "command failed: {command:?}"
+    );
+    eprintln!("Warning: command failed: {command:?}; computing coverage anyway");
+    Ok(Some(status.code().unwrap_or(1)))
+}
+
+/// Returns `command` as it could be typed into a shell, with its environment variables first
+fn command_line(command: &Command) -> String {
+    command
+        .get_envs()
+        .filter_map(|(key, value)| {
+            Some(format!(
+                "{}={}",
+                key.to_string_lossy(),
+                quote(&value?.to_string_lossy())
+            ))
+        })
+        .chain(std::iter::once(
+            quote(&command.get_program().to_string_lossy()).into_owned(),
+        ))
+        .chain(
+            command
+                .get_args()
+                .map(|arg| quote(&arg.to_string_lossy()).into_owned()),
+        )
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -0,0 +1,77 @@
+use crate::{
+    build_with_debug, cli::CargoTestArgs, exit_with_test_failure, no_pcs_files_message,
+    prepare_trace_dir, use_patched_agave_tools, SBF_OUT_DIR, SBF_TRACE_DIR,
+};
+use anchor_coverage::{
+    build::COVERAGE_DEPLOY_DIR, coverage_config::CoverageConfig, util::files_with_extension,
+};
+use anyhow::{bail, ensure, Result};
+use std::{
+    env::var_os,
+    fs::{canonicalize, create_dir_all},
+    path::Path,
+    process::Command,
+};
+
+pub fn cargo_test(
+    cargo_test_args: &CargoTestArgs,
+    root: &Path,
+    config: &CoverageConfig,
+) -> Result<()> {
+    let _guard = use_patched_agave_tools(root)?;
+
+    let sbf_trace_dir = config.trace_dir(root);
+
+    prepare_trace_dir(root, &sbf_trace_dir, cargo_test_args.clean, false)?;
+
+    create_dir_all(&sbf_trace_dir)?;
+
+    // smoelius: `cargo test` runs each test binary in its package's directory, so give the
+    // harnesses an absolute path.
+    let sbf_trace_dir = canonicalize(&sbf_trace_dir)?;
+
+    build_with_debug(root, config)?;
+
+    let mut command = Command::new("cargo");
+    command.arg("test");
+    command.args(&config.cargo_test_args);
+    command.current_dir(root);
+    command.env(SBF_TRACE_DIR, &sbf_trace_dir);
+    if var_os(SBF_OUT_DIR).is_none() {
+        command.env(SBF_OUT_DIR, root.join(COVERAGE_DEPLOY_DIR));
+    }
+    let status = command.status()?;
+    let test_exit_code = if status.success() {
+        None
+    } else {
+        ensure!(
+            cargo_test_args.allow_test_failures,
+            "command failed: {command:?}"
+        );
+        eprintln!("Warning: command failed: {command:?}; computing coverage anyway");
+        Some(status.code().unwrap_or(1))
+    };
+
+    if files_with_extension(&sbf_trace_dir, "pcs")?.is_empty() {
+        bail!(
+            "{}\n\nNote that the test harness must write program counters files to \
+             `{SBF_TRACE_DIR}`",
+            no_pcs_files_message(&sbf_trace_dir)?
+        );
+    }
+
+    anchor_coverage::run(
+        &sbf_trace_dir,
+        &anchor_coverage::Options {
+            workspace_root: root.to_path_buf(),
+            test_exit_code,
+            ..Default::default()
+        },
+    )?;
+
+    if let Some(code) = test_exit_code {
+        exit_with_test_failure(code, &[]);
+    }
+
+    Ok(())
+}
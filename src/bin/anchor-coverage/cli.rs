@@ -0,0 +1,421 @@
+use anchor_coverage::{
+    build::{parse_env_var, OPT_LEVELS},
+    coverage_config::CoverageConfig,
+    merge::{Format, MergeOptions, Remap},
+};
+use clap::{Args, Parser, Subcommand};
+use clap_complete::Shell;
+use std::path::PathBuf;
+
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    after_help = "Arguments after `--` are passed verbatim to `anchor test`, e.g.:
+
+    anchor-coverage -- --run tests/config"
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Commands>,
+
+    #[command(flatten)]
+    pub test: TestArgs,
+
+    /// Print the effective configuration, i.e., the settings from `Anchor.toml` or
+    /// `anchor-coverage.toml` with command line options applied, and exit
+    #[arg(long, global = true)]
+    pub print_config: bool,
+
+    /// Optimization level of coverage builds, overriding `[profile.release]`'s `opt-level`; lower
+    /// levels map instructions to source lines more accurately
+    #[arg(long, global = true, value_name = "LEVEL", value_parser = OPT_LEVELS)]
+    pub opt_level: Option<String>,
+
+    /// Build only this program, given by package or library name; may be repeated
+    #[arg(long = "program-name", short = 'p', global = true, value_name = "NAME")]
+    pub programs: Vec<String>,
+
+    /// Argument passed to `cargo build-sbf`, e.g., `--build-sbf-arg=--features=foo`; may be
+    /// repeated
+    #[arg(
+        long = "build-sbf-arg",
+        global = true,
+        value_name = "ARG",
+        allow_hyphen_values = true
+    )]
+    pub build_sbf_args: Vec<String>,
+
+    /// Environment variable set for `cargo build-sbf`; may be repeated
+    #[arg(
+        long = "env",
+        global = true,
+        value_name = "KEY=VALUE",
+        value_parser = parse_env_var_arg
+    )]
+    pub build_env_vars: Vec<String>,
+
+    /// Skip generating IDLs, and use those already in `target/idl`
+    #[arg(long, global = true)]
+    pub no_idl: bool,
+
+    /// Skip building, and use the debug files already in `target/coverage/deploy`
+    #[arg(long, global = true)]
+    pub skip_build: bool,
+}
+
+impl Cli {
+    /// Applies the build options to `config`
+    pub fn apply(&self, config: &mut CoverageConfig) {
+        if self.opt_level.is_some() {
+            config.opt_level.clone_from(&self.opt_level);
+        }
+        if !self.programs.is_empty() {
+            config.programs.clone_from(&self.programs);
+        }
+        if !self.build_sbf_args.is_empty() {
+            config.build_sbf_args.clone_from(&self.build_sbf_args);
+        }
+        if !self.build_env_vars.is_empty() {
+            config.build_env_vars.clone_from(&self.build_env_vars);
+        }
+        if self.no_idl {
+            config.no_idl = true;
+        }
+        if self.skip_build {
+            config.skip_build = true;
+        }
+    }
+}
+
+fn parse_env_var_arg(env_var: &str) -> anyhow::Result<String> {
+    parse_env_var(env_var)?;
+    Ok(env_var.to_owned())
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Commands {
+    /// Build with debug information, run `anchor test`, and compute coverage (the default)
+    Test(TestArgs),
+
+    /// Regenerate the lcov files and JSON report from an existing trace directory without
+    /// rebuilding or rerunning tests
+    ///
+    /// Fails if the debug files no longer match the traces.
+    Report(ReportArgs),
+
+    /// Build with debug information, start a patched validator or wait for one to be started, run
+    /// COMMAND against it (if given), and compute coverage when it finishes
+    ///
+    /// Without `--start-validator`, the command prints how to start the validator, and waits for
+    /// it. Without COMMAND, the command waits for the validator to shut down or, if it started the
+    /// validator, for Enter to be pressed.
+    Attach(AttachArgs),
+
+    /// Build with debug information, run `cargo test` with `SBF_TRACE_DIR` set, and compute
+    /// coverage
+    ///
+    /// This is for programs tested with in-process SVMs, e.g., `solana-program-test`, `litesvm`,
+    /// or `mollusk-svm`, that write program counters files to `SBF_TRACE_DIR`. No validator is
+    /// needed.
+    CargoTest(CargoTestArgs),
+
+    /// Build a native (non-Anchor) workspace with `cargo build-sbf --debug`, run COMMAND with
+    /// `SBF_TRACE_DIR` set, and compute coverage
+    ///
+    /// This is for programs written with, e.g., `solana-program` or Pinocchio, whose workspaces
+    /// have no `Anchor.toml`. COMMAND must run the programs in a patched validator or in an
+    /// in-process SVM that writes program counters files to `SBF_TRACE_DIR`.
+    Native(NativeArgs),
+
+    /// Merge lcov files and JSON reports into a single lcov file or JSON report, summing hit
+    /// counts
+    Merge(MergeArgs),
+
+    /// Check the toolchain and workspace, e.g., that the validator is patched and that the debug
+    /// files have line tables, and suggest fixes for any problems
+    Doctor,
+
+    /// Delete the files written to the trace directory, including any runs accumulated with
+    /// `--append`
+    Reset {
+        /// Trace directory to clean [default: `sbf_trace_dir` in the directory containing
+        /// `Anchor.toml`]
+        sbf_trace_dir: Option<PathBuf>,
+    },
+
+    /// Print a shell completion script
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+}
+
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Args, Debug, Default)]
+pub struct TestArgs {
+    /// Print each debug file's address-to-location map rather than computing coverage
+    #[arg(long)]
+    pub debug: bool,
+
+    /// Validator log with which to label program counters files [default:
+    /// .anchor/test-ledger/validator.log]
+    #[arg(long, value_name = "PATH")]
+    pub validator_log: Option<PathBuf>,
+
+    /// Directory to which program counters files are written [default: `sbf_trace_dir` in the
+    /// directory containing `Anchor.toml`]
+    #[arg(long, value_name = "DIR")]
+    pub trace_dir: Option<PathBuf>,
+
+    /// Delete the trace directory's contents before running tests; without this option, a
+    /// non-empty trace directory is an error
+    #[arg(long)]
+    pub clean: bool,
+
+    /// Keep earlier runs in the trace directory, write this run's traces to a new `run-N`
+    /// subdirectory, and write an aggregate lcov file and JSON report over all runs
+    #[arg(long)]
+    pub append: bool,
+
+    /// Run `anchor test --run DIR` for each directory DIR containing a `Test.toml` file, write
+    /// each one's traces to a subdirectory of the trace directory, and write a combined lcov file
+    /// and JSON report along with a table comparing their coverage
+    #[arg(long, conflicts_with_all = ["append", "debug"])]
+    pub all_test_configs: bool,
+
+    /// Compute coverage even if `anchor test` fails, and then exit with its exit code
+    #[arg(long)]
+    pub allow_test_failures: bool,
+
+    /// With `--all-test-configs`, the number of test configurations to run concurrently; each
+    /// runs its own validator on its own ports
+    #[arg(
+        long,
+        short,
+        value_name = "N",
+        default_value_t = 1,
+        requires = "all_test_configs"
+    )]
+    pub jobs: u16,
+
+    /// Arguments passed verbatim to `anchor test`; these replace any in the configuration
+    #[arg(last = true, value_name = "ANCHOR_TEST_ARGS")]
+    pub anchor_args: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct ReportArgs {
+    /// Trace directory containing program counters files [default: `sbf_trace_dir` in the
+    /// directory containing `Anchor.toml`]
+    pub sbf_trace_dir: Option<PathBuf>,
+
+    /// Directory containing the debug files [default: target/coverage/deploy]
+    #[arg(long, value_name = "DIR")]
+    pub debug_dir: Option<PathBuf>,
+
+    /// Validator log with which to label program counters files [default:
+    /// .anchor/test-ledger/validator.log, if it is no older than the traces]
+    #[arg(long, value_name = "PATH")]
+    pub validator_log: Option<PathBuf>,
+
+    /// Print each debug file's address-to-location map rather than computing coverage
+    #[arg(long)]
+    pub debug: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct AttachArgs {
+    /// Start a patched `solana-test-validator` with the settings in `Anchor.toml`'s `[test]` table
+    #[arg(long)]
+    pub start_validator: bool,
+
+    /// RPC port of the validator [default: `[test.validator]`'s `rpc_port`, or 8899]
+    #[arg(long, value_name = "PORT")]
+    pub rpc_port: Option<u16>,
+
+    /// Directory to which program counters files are written [default: `sbf_trace_dir` in the
+    /// directory containing `Anchor.toml`]
+    #[arg(long, value_name = "DIR")]
+    pub trace_dir: Option<PathBuf>,
+
+    /// Delete the trace directory's contents first; without this option, a non-empty trace
+    /// directory is an error
+    #[arg(long)]
+    pub clean: bool,
+
+    /// Validator log with which to label program counters files [default: the started
+    /// validator's, or .anchor/test-ledger/validator.log]
+    #[arg(long, value_name = "PATH")]
+    pub validator_log: Option<PathBuf>,
+
+    /// Compute coverage even if COMMAND fails, and then exit with its exit code
+    #[arg(long)]
+    pub allow_test_failures: bool,
+
+    /// Command to run against the validator, with `ANCHOR_PROVIDER_URL` set to its RPC URL
+    #[arg(last = true, value_name = "COMMAND")]
+    pub command: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct CargoTestArgs {
+    /// Directory to which program counters files are written [default: `sbf_trace_dir` in the
+    /// directory containing `Anchor.toml`]
+    #[arg(long, value_name = "DIR")]
+    pub trace_dir: Option<PathBuf>,
+
+    /// Delete the trace directory's contents first; without this option, a non-empty trace
+    /// directory is an error
+    #[arg(long)]
+    pub clean: bool,
+
+    /// Compute coverage even if `cargo test` fails, and then exit with its exit code
+    #[arg(long)]
+    pub allow_test_failures: bool,
+
+    /// Arguments passed verbatim to `cargo test`; these replace any in the configuration
+    #[arg(last = true, value_name = "CARGO_TEST_ARGS")]
+    pub cargo_args: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct NativeArgs {
+    /// Directory to which program counters files are written [default: `sbf_trace_dir` in the
+    /// current directory]
+    #[arg(long, value_name = "DIR")]
+    pub trace_dir: Option<PathBuf>,
+
+    /// Delete the trace directory's contents first; without this option, a non-empty trace
+    /// directory is an error
+    #[arg(long)]
+    pub clean: bool,
+
+    /// Validator log with which to label program counters files
+    #[arg(long, value_name = "PATH")]
+    pub validator_log: Option<PathBuf>,
+
+    /// Compute coverage even if COMMAND fails, and then exit with its exit code
+    #[arg(long)]
+    pub allow_test_failures: bool,
+
+    /// Command that runs the tests; this replaces any in the configuration
+    #[arg(last = true, value_name = "COMMAND")]
+    pub command: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct MergeArgs {
+    /// lcov files and JSON reports; files ending in `.json` are treated as JSON reports
+    #[arg(required = true, value_name = "INPUT")]
+    pub inputs: Vec<PathBuf>,
+
+    /// File to write
+    #[arg(long, short, value_name = "PATH")]
+    pub output: PathBuf,
+
+    /// Output format [default: json if PATH ends in `.json`, lcov otherwise]
+    #[arg(long, value_parser = ["lcov", "json"])]
+    pub format: Option<String>,
+
+    /// Replace the prefix FROM of source file paths with TO; the first matching rule is used.
+    /// These replace any rules in the configuration.
+    #[arg(long, value_name = "FROM=TO")]
+    pub remap: Vec<String>,
+}
+
+impl TestArgs {
+    /// Applies the command line options to `config`
+    pub fn apply(&self, config: &mut CoverageConfig) {
+        if !self.anchor_args.is_empty() {
+            config.anchor_test_args.clone_from(&self.anchor_args);
+        }
+        if self.validator_log.is_some() {
+            config.validator_log.clone_from(&self.validator_log);
+        }
+        if self.trace_dir.is_some() {
+            config.trace_dir.clone_from(&self.trace_dir);
+        }
+    }
+}
+
+impl ReportArgs {
+    /// Applies the command line options to `config`
+    pub fn apply(&self, config: &mut CoverageConfig) {
+        if self.sbf_trace_dir.is_some() {
+            config.trace_dir.clone_from(&self.sbf_trace_dir);
+        }
+        if self.debug_dir.is_some() {
+            config.debug_dir.clone_from(&self.debug_dir);
+        }
+        if self.validator_log.is_some() {
+            config.validator_log.clone_from(&self.validator_log);
+        }
+    }
+}
+
+impl AttachArgs {
+    /// Applies the command line options to `config`
+    pub fn apply(&self, config: &mut CoverageConfig) {
+        if self.trace_dir.is_some() {
+            config.trace_dir.clone_from(&self.trace_dir);
+        }
+        if self.validator_log.is_some() {
+            config.validator_log.clone_from(&self.validator_log);
+        }
+    }
+}
+
+impl CargoTestArgs {
+    /// Applies the command line options to `config`
+    pub fn apply(&self, config: &mut CoverageConfig) {
+        if !self.cargo_args.is_empty() {
+            config.cargo_test_args.clone_from(&self.cargo_args);
+        }
+        if self.trace_dir.is_some() {
+            config.trace_dir.clone_from(&self.trace_dir);
+        }
+    }
+}
+
+impl NativeArgs {
+    /// Applies the command line options to `config`
+    pub fn apply(&self, config: &mut CoverageConfig) {
+        if !self.command.is_empty() {
+            config.native_test_command.clone_from(&self.command);
+        }
+        if self.trace_dir.is_some() {
+            config.trace_dir.clone_from(&self.trace_dir);
+        }
+        if self.validator_log.is_some() {
+            config.validator_log.clone_from(&self.validator_log);
+        }
+    }
+}
+
+impl MergeArgs {
+    /// Applies the command line options to `config`
+    pub fn apply(&self, config: &mut CoverageConfig) {
+        if !self.remap.is_empty() {
+            config.remap.clone_from(&self.remap);
+        }
+    }
+
+    pub fn into_options(self, config: &CoverageConfig) -> anyhow::Result<MergeOptions> {
+        Ok(MergeOptions {
+            inputs: self.inputs,
+            remaps: config
+                .remap
+                .iter()
+                .map(|remap| remap.parse::<Remap>())
+                .collect::<anyhow::Result<_>>()?,
+            output: self.output,
+            format: self
+                .format
+                .as_deref()
+                .map(str::parse::<Format>)
+                .transpose()?,
+        })
+    }
+}
@@ -0,0 +1,298 @@
+use crate::{solana_test_validator_is_patched, use_patched_agave_tools, which};
+use anchor_coverage::{
+    build::{debug_file_problems, program_debug_settings, ProgramDebug, COVERAGE_DEPLOY_DIR},
+    coverage_config::CoverageConfig,
+    doctor::{
+        agave_version, lockfile_version, overflow_checks_enabled, render,
+        solana_program_dependents, Check, Status,
+    },
+    util::{files_with_extension, StripCurrentDir},
+};
+use anyhow::{bail, Result};
+use std::{fs::read_to_string, path::Path, process::Command};
+use toml::{Table, Value};
+
+const RELEASES_URL: &str = "https://github.com/trail-of-forks/sbpf-coverage/releases";
+
+/// Checks the toolchain and workspace, prints the results, and fails if any check failed
+pub fn doctor(root: &Path, config: &CoverageConfig) -> Result<()> {
+    // smoelius: Check the tools that a run would use.
+    let _guard = use_patched_agave_tools(root)?;
+
+    let anchor_toml = root.join("Anchor.toml");
+    let anchor_workspace = anchor_toml.is_file();
+
+    let mut checks = vec![patched_validator(), cargo_build_sbf()];
+    if anchor_workspace {
+        checks.push(anchor_version(root));
+        checks.push(overflow_checks(root)?);
+        checks.push(anchor_dependencies(root));
+    }
+    match program_debug_settings(root) {
+        Ok(programs) => {
+            checks.push(debug_settings(&programs));
+            checks.push(debug_files(root, config, &programs)?);
+        }
+        Err(error) => checks.push(Check::fail(
+            "Debug settings",
+            format!("failed to read the workspace's packages: {error}"),
+            "check that the workspace root has a valid Cargo.toml",
+        )),
+    }
+    if anchor_workspace {
+        checks.push(package_manager(&anchor_toml)?);
+    }
+
+    print!("{}", render(&checks));
+
+    let failures = checks
+        .iter()
+        .filter(|check| check.status == Status::Fail)
+        .count();
+    if failures != 0 {
+        bail!("{failures} of {} checks failed", checks.len());
+    }
+
+    Ok(())
+}
+
+fn patched_validator() -> Check {
+    const NAME: &str = "Patched validator";
+
+    let Ok(path) = which("solana-test-validator") else {
+        return Check::fail(
+            NAME,
+            "`solana-test-validator` not found",
+            format!(
+                "download patched Agave tools from {RELEASES_URL} and unpack them in the \
+                 workspace root"
+            ),
+        );
+    };
+    let display = path.display();
+    if !solana_test_validator_is_patched(&path).unwrap_or(false) {
+        return Check::fail(
+            NAME,
+            format!("`{display}` does not appear to be patched"),
+            format!(
+                "download patched Agave tools from {RELEASES_URL} and unpack them in the \
+                 workspace root"
+            ),
+        );
+    }
+    let version = agave_version();
+    match version_output(Command::new(&path).arg("--version")) {
+        Some(output) if output.split_whitespace().any(|word| word == version) => {
+            Check::pass(NAME, format!("{display} ({version})"))
+        }
+        output => Check::warn(
+            NAME,
+            format!(
+                "`{display}` is patched, but its version ({}) is not {version}",
+                output.as_deref().unwrap_or("unknown")
+            ),
+            format!("use the patched Agave tools for {version} from {RELEASES_URL}"),
+        ),
+    }
+}
+
+fn cargo_build_sbf() -> Check {
+    const NAME: &str = "cargo-build-sbf";
+
+    match version_output(Command::new("cargo").args(["build-sbf", "--version"])) {
+        Some(output) => Check::pass(NAME, output),
+        None => Check::fail(
+            NAME,
+            "`cargo build-sbf --version` failed",
+            "install the Solana CLI tools, e.g., the patched Agave tools",
+        ),
+    }
+}
+
+fn anchor_version(root: &Path) -> Check {
+    const NAME: &str = "Anchor version";
+
+    let Some(output) = version_output(Command::new("anchor").arg("--version")) else {
+        return Check::fail(
+            NAME,
+            "`anchor --version` failed",
+            "install the Anchor CLI, e.g., with `avm`",
+        );
+    };
+    let cli_version = output.split_whitespace().last().unwrap_or_default();
+    let lang_version = read_to_string(root.join("Cargo.lock"))
+        .ok()
+        .and_then(|contents| lockfile_version(&contents, "anchor-lang").ok().flatten());
+    match lang_version {
+        Some(lang_version) if lang_version == cli_version => Check::pass(
+            NAME,
+            format!("Anchor CLI and `anchor-lang` are both {cli_version}"),
+        ),
+        Some(lang_version) => Check::warn(
+            NAME,
+            format!("Anchor CLI is {cli_version}, but `anchor-lang` is {lang_version}"),
+            format!("run `avm use {lang_version}`"),
+        ),
+        None => Check::warn(
+            NAME,
+            format!("Anchor CLI is {cli_version}, but `anchor-lang` is not in Cargo.lock"),
+            "run `cargo generate-lockfile`",
+        ),
+    }
+}
+
+// smoelius: `anchor build` requires `overflow-checks`, but `anchor-coverage`'s stand-in for
+// Anchor's `check_overflow` does not, so a workspace that builds under `anchor-coverage` may not
+// build under `anchor build`.
+fn overflow_checks(root: &Path) -> Result<Check> {
+    const NAME: &str = "Overflow checks";
+
+    let contents = read_to_string(root.join("Cargo.toml"))?;
+    if overflow_checks_enabled(&contents)? {
+        Ok(Check::pass(
+            NAME,
+            "`overflow-checks` is enabled in the release profile",
+        ))
+    } else {
+        Ok(Check::warn(
+            NAME,
+            "`overflow-checks` is not enabled in the release profile, which `anchor build` \
+             requires",
+            "add `overflow-checks = true` under `[profile.release]` in the workspace root's \
+             Cargo.toml",
+        ))
+    }
+}
+
+// smoelius: These are the dependency problems Anchor's `check_deps` warns about, along with an
+// `anchor-spl` whose version differs from `anchor-lang`'s.
+fn anchor_dependencies(root: &Path) -> Check {
+    const NAME: &str = "Anchor dependencies";
+
+    let cargo_lock = read_to_string(root.join("Cargo.lock")).ok();
+    let version = |package| {
+        cargo_lock
+            .as_deref()
+            .and_then(|contents| lockfile_version(contents, package).ok().flatten())
+    };
+    if let (Some(lang_version), Some(spl_version)) = (version("anchor-lang"), version("anchor-spl"))
+        && lang_version != spl_version
+    {
+        return Check::warn(
+            NAME,
+            format!("`anchor-lang` is {lang_version}, but `anchor-spl` is {spl_version}"),
+            format!("use version {lang_version} of both `anchor-lang` and `anchor-spl`"),
+        );
+    }
+    match solana_program_dependents(root) {
+        Ok(dependents) if dependents.is_empty() => Check::pass(
+            NAME,
+            "`anchor-spl`, if used, matches `anchor-lang`, and no program depends on \
+             `solana-program` directly",
+        ),
+        Ok(dependents) => Check::warn(
+            NAME,
+            format!(
+                "programs depend on `solana-program` directly, which may conflict with \
+                 `anchor-lang`'s: {}",
+                dependents.join(", ")
+            ),
+            "remove the `solana-program` dependencies, and use `anchor_lang::solana_program` \
+             instead",
+        ),
+        Err(error) => Check::warn(
+            NAME,
+            format!("failed to read the workspace's packages: {error}"),
+            "check that the workspace root has a valid Cargo.toml",
+        ),
+    }
+}
+
+fn debug_settings(programs: &[ProgramDebug]) -> Check {
+    const NAME: &str = "Debug settings";
+
+    let problems = programs
+        .iter()
+        .filter_map(ProgramDebug::problem)
+        .collect::<Vec<_>>();
+    if problems.is_empty() {
+        Check::pass(
+            NAME,
+            format!("all {} programs are built with line tables", programs.len()),
+        )
+    } else {
+        Check::fail(
+            NAME,
+            problems.join("; "),
+            "remove the overrides, or set `debug = true` in them",
+        )
+    }
+}
+
+fn debug_files(root: &Path, config: &CoverageConfig, programs: &[ProgramDebug]) -> Result<Check> {
+    const NAME: &str = "Debug files";
+
+    let debug_dir = config
+        .debug_dir
+        .clone()
+        .unwrap_or_else(|| root.join(COVERAGE_DEPLOY_DIR));
+    if !debug_dir.is_dir() || files_with_extension(&debug_dir, "debug")?.is_empty() {
+        return Ok(Check::warn(
+            NAME,
+            format!(
+                "found no debug files in: {}",
+                debug_dir.strip_current_dir().display()
+            ),
+            "run `anchor-coverage`, which builds them, and then rerun `anchor-coverage doctor`",
+        ));
+    }
+    let problems = debug_file_problems(programs, &debug_dir)?;
+    if problems.is_empty() {
+        Ok(Check::pass(
+            NAME,
+            format!(
+                "all {} programs' debug files have line tables",
+                programs.len()
+            ),
+        ))
+    } else {
+        Ok(Check::fail(
+            NAME,
+            problems.join("; "),
+            "rebuild with `anchor-coverage`",
+        ))
+    }
+}
+
+fn package_manager(anchor_toml: &Path) -> Result<Check> {
+    const NAME: &str = "Package manager";
+
+    let table = read_to_string(anchor_toml)?.parse::<Table>()?;
+    let package_manager = table
+        .get("toolchain")
+        .and_then(Value::as_table)
+        .and_then(|table| table.get("package_manager"))
+        .and_then(Value::as_str)
+        .unwrap_or("yarn");
+    match which(package_manager) {
+        Ok(path) => Ok(Check::pass(NAME, path.display().to_string())),
+        Err(_) => Ok(Check::fail(
+            NAME,
+            format!("`{package_manager}` not found"),
+            format!(
+                "install `{package_manager}`, or set `package_manager` under `[toolchain]` in \
+                 Anchor.toml"
+            ),
+        )),
+    }
+}
+
+/// Runs `command` and returns the first line of its standard output, if it succeeds
+fn version_output(command: &mut Command) -> Option<String> {
+    let output = command.output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    stdout.lines().next().map(ToOwned::to_owned)
+}
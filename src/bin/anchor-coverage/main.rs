@@ -0,0 +1,542 @@
+use anchor_coverage::{
+    append::{aggregate, next_run_dir, run_dirs},
+    build::{
+        build_env, build_fingerprint, build_is_fresh, debug_file_problems, parse_env_var,
+        program_debug_settings, write_build_stamp, ProgramDebug, COVERAGE_DEPLOY_DIR,
+    },
+    coverage_config::{load_coverage_config, workspace_root, CoverageConfig, LoadedCoverageConfig},
+    deploy::stage_programs,
+    merge::merge,
+    report::Report,
+    trace_dir,
+    trace_writer::SBF_TRACE_DIR,
+    util::{files_with_extension, var_guard::VarGuard, StripCurrentDir},
+};
+use anyhow::{bail, ensure, Result};
+use clap::{CommandFactory, Parser};
+use clap_complete::generate;
+use std::{
+    env::{join_paths, split_paths, var_os},
+    ffi::OsString,
+    fmt::Write,
+    fs::{canonicalize, create_dir_all, metadata, read, read_dir, File},
+    io::stdout,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// The directory in which `solana-program-test` looks for programs
+const SBF_OUT_DIR: &str = "SBF_OUT_DIR";
+
+mod all_test_configs;
+use all_test_configs::test_all_configs;
+
+mod attach;
+use attach::attach;
+
+mod cargo_test;
+use cargo_test::cargo_test;
+
+mod cli;
+use cli::{Cli, Commands, ReportArgs, TestArgs};
+
+mod doctor;
+use doctor::doctor;
+
+mod native;
+use native::native;
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    let root = workspace_root()?;
+
+    let LoadedCoverageConfig {
+        path: config_path,
+        mut config,
+    } = load_coverage_config(&root)?;
+
+    match &cli.command {
+        None => cli.test.apply(&mut config),
+        Some(Commands::Test(test_args)) => test_args.apply(&mut config),
+        Some(Commands::Report(report_args)) => report_args.apply(&mut config),
+        Some(Commands::Attach(attach_args)) => attach_args.apply(&mut config),
+        Some(Commands::CargoTest(cargo_test_args)) => cargo_test_args.apply(&mut config),
+        Some(Commands::Native(native_args)) => native_args.apply(&mut config),
+        Some(Commands::Merge(merge_args)) => merge_args.apply(&mut config),
+        Some(Commands::Reset { sbf_trace_dir }) => {
+            if sbf_trace_dir.is_some() {
+                config.trace_dir.clone_from(sbf_trace_dir);
+            }
+        }
+        Some(Commands::Doctor | Commands::Completions { .. }) => {}
+    }
+
+    cli.apply(&mut config);
+
+    if cli.print_config {
+        if let Some(config_path) = config_path {
+            println!("# Read from: {}", config_path.strip_current_dir().display());
+        }
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
+
+    match cli.command {
+        None => test(&cli.test, &root, &config),
+        Some(Commands::Test(test_args)) => test(&test_args, &root, &config),
+        Some(Commands::Report(report_args)) => report(&report_args, &root, &config),
+        Some(Commands::Attach(attach_args)) => attach(&attach_args, &root, &config),
+        Some(Commands::CargoTest(cargo_test_args)) => cargo_test(&cargo_test_args, &root, &config),
+        Some(Commands::Native(native_args)) => native(&native_args, &root, &config),
+        Some(Commands::Merge(merge_args)) => merge(&merge_args.into_options(&config)?),
+        Some(Commands::Doctor) => doctor(&root, &config),
+        Some(Commands::Reset { .. }) => reset(&config.trace_dir(&root), &root),
+        Some(Commands::Completions { shell }) => {
+            generate(
+                shell,
+                &mut Cli::command(),
+                env!("CARGO_BIN_NAME"),
+                &mut stdout(),
+            );
+            Ok(())
+        }
+    }
+}
+
+fn test(test_args: &TestArgs, root: &Path, config: &CoverageConfig) -> Result<()> {
+    let _guard = use_patched_agave_tools(root)?;
+
+    let sbf_trace_dir = config.trace_dir(root);
+
+    prepare_trace_dir(root, &sbf_trace_dir, test_args.clean, test_args.append)?;
+
+    if test_args.all_test_configs {
+        return test_all_configs(test_args, root, config, &sbf_trace_dir);
+    }
+
+    // smoelius: In append mode, this run's traces go in their own subdirectory.
+    let run_dir = if test_args.append {
+        next_run_dir(&sbf_trace_dir)?
+    } else {
+        sbf_trace_dir.clone()
+    };
+
+    create_dir_all(&run_dir)?;
+
+    build_with_debug(root, config)?;
+
+    let staged = stage_programs(root)?;
+    let test_exit_code = anchor_test_skip_build(
+        &config.anchor_test_args,
+        &run_dir,
+        None,
+        test_args.allow_test_failures,
+    )?;
+    drop(staged);
+
+    let pcs_paths = anchor_coverage::util::files_with_extension(&run_dir, "pcs")?;
+
+    if pcs_paths.is_empty() {
+        bail!(no_pcs_files_message(&run_dir)?);
+    }
+
+    anchor_coverage::run(
+        &run_dir,
+        &anchor_coverage::Options {
+            debug: test_args.debug,
+            validator_log: validator_log(root, config)?,
+            workspace_root: root.to_path_buf(),
+            test_exit_code,
+            ..Default::default()
+        },
+    )?;
+
+    if test_args.append {
+        aggregate(&sbf_trace_dir)?;
+    }
+
+    if let Some(code) = test_exit_code {
+        exit_with_test_failure(code, &[]);
+    }
+
+    Ok(())
+}
+
+/// Prepends the `bin` directory of the patched Agave tools in `root`, if any, to `PATH`
+///
+/// `PATH` is restored when the returned guard is dropped.
+fn use_patched_agave_tools(root: &Path) -> Result<Option<VarGuard>> {
+    // smoelius: Set `PATH` now, once and for all. This way subsequent calls to `which` will return
+    // paths to the tools actually used.
+    let Some(path_buf) = anchor_coverage::util::patched_agave_tools(root)? else {
+        return Ok(None);
+    };
+    eprintln!(
+        "Found patched Agave tools: {}",
+        path_buf.strip_current_dir().display()
+    );
+    let prepended_paths = prepend_paths(path_buf.join("bin"))?;
+    Ok(Some(VarGuard::set("PATH", Some(prepended_paths))))
+}
+
+/// Deletes the trace directory's contents if `clean` is true; otherwise, fails if the trace
+/// directory is not empty, unless `append` is true
+fn prepare_trace_dir(root: &Path, sbf_trace_dir: &Path, clean: bool, append: bool) -> Result<()> {
+    if clean {
+        reset(sbf_trace_dir, root)?;
+    } else if !append && sbf_trace_dir.try_exists()? && read_dir(sbf_trace_dir)?.next().is_some() {
+        bail!(
+            "trace directory `{}` is not empty; pass `--clean` to delete its contents, or \
+             `--append` to keep them",
+            sbf_trace_dir.strip_current_dir().display()
+        );
+    }
+    Ok(())
+}
+
+/// Reports that tests failed, naming the test configurations that failed if known, and exits
+/// with the tests' exit code
+fn exit_with_test_failure(code: i32, test_configs: &[String]) -> ! {
+    let mut message = format!("\nTESTS FAILED (exit code {code})");
+    if !test_configs.is_empty() {
+        write!(message, " in: {}", test_configs.join(", ")).unwrap();
+    }
+    eprintln!("{message}");
+    eprintln!("Coverage was computed from the traces collected before and during the failures.");
+    std::process::exit(code);
+}
+
+fn no_pcs_files_message(sbf_trace_dir: &Path) -> Result<String> {
+    let mut message = format!(
+        "Found no program counter files in: {}",
+        sbf_trace_dir.strip_current_dir().display()
+    );
+    let path = which("solana-test-validator")?;
+    if !solana_test_validator_is_patched(&path)? {
+        #[rustfmt::skip]
+        write!(
+            &mut message,
+            "\n
+`{}` does not appear to be patched.
+
+Either download, unzip, and untar prebuilt patched binaries from:
+
+    https://github.com/trail-of-forks/sbpf-coverage/releases
+
+Or build patched binaries from source using the instructions at:
+
+    https://github.com/trail-of-forks/sbpf-coverage",
+            path.display()
+        )
+        .unwrap();
+    }
+    Ok(message)
+}
+
+fn reset(sbf_trace_dir: &Path, root: &Path) -> Result<()> {
+    if sbf_trace_dir.try_exists()? {
+        eprintln!("Cleaning `{}`", sbf_trace_dir.strip_current_dir().display());
+        trace_dir::clean(sbf_trace_dir, root)?;
+    }
+    Ok(())
+}
+
+fn report(report_args: &ReportArgs, root: &Path, config: &CoverageConfig) -> Result<()> {
+    let sbf_trace_dir = config.trace_dir(root);
+
+    ensure!(
+        sbf_trace_dir.is_dir(),
+        "trace directory does not exist: {}",
+        sbf_trace_dir.display()
+    );
+
+    // smoelius: If the trace directory was written in append mode, regenerate each run and then
+    // the aggregate. The validator log is overwritten by each run, so it can label only the last.
+    let run_dirs = run_dirs(&sbf_trace_dir)?;
+    if let Some(last_run_dir) = run_dirs.last() {
+        let validator_log = report_validator_log(root, config, last_run_dir)?;
+        for (i, run_dir) in run_dirs.iter().enumerate() {
+            report_dir(
+                run_dir,
+                report_args,
+                root,
+                config,
+                validator_log.clone().filter(|_| i + 1 == run_dirs.len()),
+            )?;
+        }
+        return aggregate(&sbf_trace_dir);
+    }
+
+    report_dir(
+        &sbf_trace_dir,
+        report_args,
+        root,
+        config,
+        report_validator_log(root, config, &sbf_trace_dir)?,
+    )
+}
+
+/// Returns the validator log with which `report` labels the traces in `sbf_trace_dir`
+///
+/// The default validator log is overwritten by each `anchor test`, so it is used only if it was
+/// modified no earlier than the traces, i.e., if it could be from the run that wrote them.
+fn report_validator_log(
+    root: &Path,
+    config: &CoverageConfig,
+    sbf_trace_dir: &Path,
+) -> Result<Option<PathBuf>> {
+    let validator_log = validator_log(root, config)?;
+    if config.validator_log.is_some() {
+        return Ok(validator_log);
+    }
+    let Some(validator_log) = validator_log else {
+        return Ok(None);
+    };
+    let log_modified = metadata(&validator_log)?.modified()?;
+    for pcs_path in anchor_coverage::util::files_with_extension(sbf_trace_dir, "pcs")? {
+        if metadata(&pcs_path)?.modified()? > log_modified {
+            eprintln!(
+                "Warning: `{}` is older than the traces and may be from a different run; not \
+                 labeling traces. Pass `--validator-log` to use it anyway.",
+                validator_log.strip_current_dir().display()
+            );
+            return Ok(None);
+        }
+    }
+    Ok(Some(validator_log))
+}
+
+fn report_dir(
+    sbf_trace_dir: &Path,
+    report_args: &ReportArgs,
+    root: &Path,
+    config: &CoverageConfig,
+    validator_log: Option<PathBuf>,
+) -> Result<()> {
+    let pcs_paths = anchor_coverage::util::files_with_extension(sbf_trace_dir, "pcs")?;
+    ensure!(
+        !pcs_paths.is_empty(),
+        "Found no program counter files in: {}",
+        sbf_trace_dir.strip_current_dir().display()
+    );
+
+    anchor_coverage::run(
+        sbf_trace_dir,
+        &anchor_coverage::Options {
+            debug: report_args.debug,
+            validator_log,
+            debug_dir: config.debug_dir.clone(),
+            require_match: true,
+            workspace_root: root.to_path_buf(),
+            test_exit_code: Report::recorded_test_exit_code(sbf_trace_dir)?,
+        },
+    )
+}
+
+fn validator_log(root: &Path, config: &CoverageConfig) -> Result<Option<PathBuf>> {
+    if let Some(validator_log) = &config.validator_log {
+        ensure!(
+            validator_log.try_exists()?,
+            "validator log does not exist: {}",
+            validator_log.display()
+        );
+        return Ok(Some(validator_log.clone()));
+    }
+    let validator_log = root.join(anchor_coverage::validator_log::VALIDATOR_LOG_PATH);
+    Ok(validator_log.try_exists()?.then_some(validator_log))
+}
+
+fn prepend_paths(path: PathBuf) -> Result<OsString> {
+    let Some(paths) = var_os("PATH") else {
+        bail!("`PATH` is unset");
+    };
+    let paths_split = split_paths(&paths);
+    let paths_chained = std::iter::once(path).chain(paths_split);
+    let paths_joined = join_paths(paths_chained)?;
+    Ok(paths_joined)
+}
+
+/// Builds the workspace at `root` with debug information, using the settings of a coverage build
+fn build_with_debug(root: &Path, config: &CoverageConfig) -> Result<()> {
+    build_unless_fresh(root, config, "anchor", || {
+        anchor_build_with_debug(root, config)
+    })
+}
+
+fn anchor_build_with_debug(root: &Path, config: &CoverageConfig) -> Result<()> {
+    // smoelius: Anchor's build skips malformed settings, so check them here, as `native` does.
+    for env_var in &config.build_env_vars {
+        parse_env_var(env_var)?;
+    }
+
+    let programs = warn_if_no_debug(root, &config.programs)?;
+
+    // smoelius: The variables are restored when the guards are dropped, i.e., after the build.
+    let guards = build_env(root, config.opt_level.as_deref())?
+        .into_iter()
+        .map(|(key, value)| VarGuard::set(key, Some(value)))
+        .collect::<Vec<_>>();
+
+    // smoelius: Anchor builds either one program or all of them.
+    #[cfg(feature = "__anchor_cli")]
+    for program_name in selected_programs(&config.programs) {
+        anchor_coverage::__build_with_debug(
+            &anchor_coverage::ConfigOverride::default(),
+            config.no_idl,
+            None,
+            None,
+            false,
+            true, // skip_lint
+            program_name,
+            None,
+            None,
+            anchor_coverage::BootstrapMode::None,
+            None,
+            None,
+            config.build_env_vars.clone(),
+            config.build_sbf_args.clone(),
+            true, // no_docs
+            anchor_coverage::ProgramArch::Sbf,
+        )?;
+    }
+
+    drop(guards);
+
+    warn_if_debug_files_lack_line_tables(root, &programs)
+}
+
+/// Runs `build`, unless `skip_build` is set or the last coverage build had the same inputs
+///
+/// `mode` distinguishes the kinds of builds, e.g., Anchor's and `cargo build-sbf`'s, so that one
+/// is not mistaken for the other.
+fn build_unless_fresh(
+    root: &Path,
+    config: &CoverageConfig,
+    mode: &str,
+    build: impl FnOnce() -> Result<()>,
+) -> Result<()> {
+    let deploy_dir = root.join(COVERAGE_DEPLOY_DIR);
+    if config.skip_build {
+        ensure!(
+            deploy_dir.is_dir() && !files_with_extension(&deploy_dir, "debug")?.is_empty(),
+            "`--skip-build` was given, but found no debug files in: {}",
+            deploy_dir.strip_current_dir().display()
+        );
+        eprintln!(
+            "Skipping build; using the debug files in {}",
+            deploy_dir.strip_current_dir().display()
+        );
+        return Ok(());
+    }
+
+    let no_idl = config.no_idl.to_string();
+    let mut settings = vec![
+        mode,
+        config.opt_level.as_deref().unwrap_or_default(),
+        &no_idl,
+    ];
+    for strings in [
+        &config.programs,
+        &config.build_sbf_args,
+        &config.build_env_vars,
+    ] {
+        settings.push("--");
+        settings.extend(strings.iter().map(String::as_str));
+    }
+    let fingerprint = build_fingerprint(root, &settings)?;
+    if build_is_fresh(root, &fingerprint)? {
+        eprintln!(
+            "Skipping build; the debug files in {} are up to date",
+            deploy_dir.strip_current_dir().display()
+        );
+        return Ok(());
+    }
+
+    build()?;
+
+    write_build_stamp(root, &fingerprint)
+}
+
+/// Returns the program names to pass to Anchor's build, where `None` means all programs
+#[cfg(feature = "__anchor_cli")]
+fn selected_programs(programs: &[String]) -> Vec<Option<String>> {
+    if programs.is_empty() {
+        vec![None]
+    } else {
+        programs.iter().cloned().map(Some).collect()
+    }
+}
+
+/// Warns about each program whose settings will keep its debug file from having line tables, and
+/// returns the programs' settings
+///
+/// If `selected` is not empty, only the programs it names, by package or library name, are
+/// considered.
+fn warn_if_no_debug(root: &Path, selected: &[String]) -> Result<Vec<ProgramDebug>> {
+    let mut programs = program_debug_settings(root)?;
+    if !selected.is_empty() {
+        programs.retain(|program| {
+            selected
+                .iter()
+                .any(|name| *name == program.package || *name == program.lib_name)
+        });
+    }
+    for problem in programs.iter().filter_map(ProgramDebug::problem) {
+        eprintln!("Warning: {problem}; its debug file will have no line tables");
+    }
+    Ok(programs)
+}
+
+/// Warns about each program whose debug file is missing or has no line tables after a build
+fn warn_if_debug_files_lack_line_tables(root: &Path, programs: &[ProgramDebug]) -> Result<()> {
+    for problem in debug_file_problems(programs, &root.join(COVERAGE_DEPLOY_DIR))? {
+        eprintln!("Warning: {problem}; no coverage will be computed for it");
+    }
+    Ok(())
+}
+
+/// Runs `anchor test --skip-build`, writing its output to `log_path` if given
+///
+/// If the tests fail and `allow_failure` is true, returns their exit code rather than an error.
+fn anchor_test_skip_build(
+    args: &[String],
+    sbf_trace_dir: &Path,
+    log_path: Option<&Path>,
+    allow_failure: bool,
+) -> Result<Option<i32>> {
+    let mut command = Command::new("anchor");
+    command.args(["test", "--skip-build"]);
+    command.args(args);
+    command.env(SBF_TRACE_DIR, sbf_trace_dir);
+    if let Some(log_path) = log_path {
+        let file = File::create(log_path)?;
+        command.stdout(file.try_clone()?);
+        command.stderr(file);
+    }
+    let status = command.status()?;
+    if status.success() {
+        return Ok(None);
+    }
+    ensure!(allow_failure, "command failed: {command:?}");
+    eprintln!("Warning: command failed: {command:?}; computing coverage anyway");
+    // smoelius: A process killed by a signal has no exit code.
+    Ok(Some(status.code().unwrap_or(1)))
+}
+
+fn solana_test_validator_is_patched(path: &Path) -> Result<bool> {
+    let contents = read(path)?;
+    let needle = "SBF_TRACE_DIR";
+    Ok(contents
+        .windows(needle.len())
+        .any(|w| w == needle.as_bytes()))
+}
+
+fn which(filename: &str) -> Result<PathBuf> {
+    let mut command = Command::new("which");
+    let output = command.arg(filename).output()?;
+    ensure!(output.status.success(), "command failed: {command:?}");
+    let stdout = std::str::from_utf8(&output.stdout)?;
+    let path = canonicalize(stdout.trim_end())?;
+    Ok(path)
+}
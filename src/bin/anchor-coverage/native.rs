@@ -0,0 +1,122 @@
+use crate::{
+    build_unless_fresh, cli::NativeArgs, exit_with_test_failure, no_pcs_files_message,
+    prepare_trace_dir, use_patched_agave_tools, validator_log,
+    warn_if_debug_files_lack_line_tables, warn_if_no_debug, SBF_OUT_DIR, SBF_TRACE_DIR,
+};
+use anchor_coverage::{
+    build::{build_env, parse_env_var, COVERAGE_DEPLOY_DIR},
+    coverage_config::CoverageConfig,
+    util::files_with_extension,
+};
+use anyhow::{bail, ensure, Result};
+use std::{
+    env::var_os,
+    fs::{canonicalize, create_dir_all},
+    path::Path,
+    process::Command,
+};
+
+pub fn native(native_args: &NativeArgs, root: &Path, config: &CoverageConfig) -> Result<()> {
+    let Some((program, args)) = config.native_test_command.split_first() else {
+        bail!(
+            "no test command; pass one after `--`, or set `native_test_command` in \
+             `anchor-coverage.toml`"
+        );
+    };
+
+    let _guard = use_patched_agave_tools(root)?;
+
+    let sbf_trace_dir = config.trace_dir(root);
+
+    prepare_trace_dir(root, &sbf_trace_dir, native_args.clean, false)?;
+
+    create_dir_all(&sbf_trace_dir)?;
+
+    // smoelius: The test command may run things in other directories, so give it an absolute
+    // path.
+    let sbf_trace_dir = canonicalize(&sbf_trace_dir)?;
+
+    build_sbf_with_debug(root, config)?;
+
+    let mut command = Command::new(program);
+    command.args(args);
+    command.current_dir(root);
+    command.env(SBF_TRACE_DIR, &sbf_trace_dir);
+    if var_os(SBF_OUT_DIR).is_none() {
+        command.env(SBF_OUT_DIR, root.join(COVERAGE_DEPLOY_DIR));
+    }
+    let status = command.status()?;
+    let test_exit_code = if status.success() {
+        None
+    } else {
+        ensure!(
+            native_args.allow_test_failures,
+            "command failed: {command:?}"
+        );
+        eprintln!("Warning: command failed: {command:?}; computing coverage anyway");
+        Some(status.code().unwrap_or(1))
+    };
+
+    if files_with_extension(&sbf_trace_dir, "pcs")?.is_empty() {
+        bail!(no_pcs_files_message(&sbf_trace_dir)?);
+    }
+
+    // smoelius: There is no default validator log outside of an Anchor workspace.
+    let validator_log = if config.validator_log.is_some() {
+        validator_log(root, config)?
+    } else {
+        None
+    };
+
+    anchor_coverage::run(
+        &sbf_trace_dir,
+        &anchor_coverage::Options {
+            validator_log,
+            workspace_root: root.to_path_buf(),
+            test_exit_code,
+            ..Default::default()
+        },
+    )?;
+
+    if let Some(code) = test_exit_code {
+        exit_with_test_failure(code, &[]);
+    }
+
+    Ok(())
+}
+
+/// Runs `cargo build-sbf --debug` in `root`, using the settings of a coverage build
+///
+/// `--debug` makes `cargo build-sbf` write a `.debug` file next to each `.so` file in
+/// `target/coverage/deploy`.
+fn build_sbf_with_debug(root: &Path, config: &CoverageConfig) -> Result<()> {
+    build_unless_fresh(root, config, "native", || {
+        cargo_build_sbf_with_debug(root, config)
+    })
+}
+
+fn cargo_build_sbf_with_debug(root: &Path, config: &CoverageConfig) -> Result<()> {
+    // smoelius: `cargo build-sbf` builds all of the workspace's programs unless told otherwise by
+    // `build_sbf_args`, e.g., with `--manifest-path`.
+    ensure!(
+        config.programs.is_empty(),
+        "`native` cannot select programs by name; pass `cargo build-sbf` arguments instead, e.g., \
+         `--build-sbf-arg=--manifest-path=programs/NAME/Cargo.toml`"
+    );
+
+    let programs = warn_if_no_debug(root, &[])?;
+
+    let mut command = Command::new("cargo");
+    command.args(["build-sbf", "--debug"]);
+    command.args(&config.build_sbf_args);
+    command.current_dir(root);
+    command.envs(build_env(root, config.opt_level.as_deref())?);
+    for env_var in &config.build_env_vars {
+        let (key, value) = parse_env_var(env_var)?;
+        command.env(key, value);
+    }
+    let status = command.status()?;
+    ensure!(status.success(), "command failed: {command:?}");
+
+    warn_if_debug_files_lack_line_tables(root, &programs)
+}
@@ -0,0 +1,538 @@
+//! Settings for coverage builds.
+//!
+//! Coverage builds use their own target directory, so that they neither clobber nor reuse the
+//! artifacts of ordinary release builds, and get their debug information from Cargo's
+//! environment variable profile overrides rather than from `Cargo.toml`. The final `.so` and
+//! `.debug` files are written to [`COVERAGE_DEPLOY_DIR`] rather than `target/deploy`, so that the
+//! release `.so` files there are left alone. See the [`deploy`](crate::deploy) module for how
+//! `anchor test` is given the coverage `.so` files.
+
+use crate::{doctor::has_line_tables, util::files_with_extension};
+use anyhow::{anyhow, ensure, Context, Result};
+use cargo_metadata::{CrateType, MetadataCommand};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    env::{home_dir, var_os, vars_os},
+    ffi::{OsStr, OsString},
+    fmt::Write,
+    fs::{create_dir_all, metadata, read, read_dir, read_to_string, write},
+    path::{Path, PathBuf},
+    process::Command,
+    time::UNIX_EPOCH,
+};
+use toml::{Table, Value};
+
+/// The target directory of coverage builds, relative to the workspace root
+pub const COVERAGE_TARGET_DIR: &str = "target/coverage";
+
+/// The directory to which release builds write `.so` files, and from which `anchor test` loads
+/// them, relative to the workspace root
+pub const DEPLOY_DIR: &str = "target/deploy";
+
+/// The directory to which coverage builds write `.so` and `.debug` files, relative to the
+/// workspace root
+pub const COVERAGE_DEPLOY_DIR: &str = "target/coverage/deploy";
+
+/// The optimization levels Cargo accepts
+pub const OPT_LEVELS: [&str; 6] = ["0", "1", "2", "3", "s", "z"];
+
+/// Returns the environment variables to set for a coverage build of the workspace at `root`
+///
+/// If `opt_level` is given, it overrides `[profile.release]`'s `opt-level`. Lower levels map
+/// instructions to source lines more accurately.
+pub fn build_env(root: &Path, opt_level: Option<&str>) -> Result<Vec<(&'static str, OsString)>> {
+    let mut env = vec![
+        (
+            "CARGO_TARGET_DIR",
+            root.join(COVERAGE_TARGET_DIR).into_os_string(),
+        ),
+        ("CARGO_PROFILE_RELEASE_DEBUG", OsString::from("true")),
+        // smoelius: `cargo build-sbf` reads its `--sbf-out-dir` from `SBF_OUT_PATH`. Its default
+        // is the target directory's `deploy` subdirectory, but an `SBF_OUT_PATH` in the
+        // environment could point elsewhere, e.g., at `target/deploy`.
+        (
+            "SBF_OUT_PATH",
+            root.join(COVERAGE_DEPLOY_DIR).into_os_string(),
+        ),
+    ];
+    if let Some(opt_level) = opt_level {
+        ensure!(
+            OPT_LEVELS.contains(&opt_level),
+            "invalid optimization level `{opt_level}`; expected one of: {}",
+            OPT_LEVELS.join(", ")
+        );
+        env.push(("CARGO_PROFILE_RELEASE_OPT_LEVEL", OsString::from(opt_level)));
+    }
+    Ok(env)
+}
+
+/// Splits a `KEY=VALUE` environment variable setting into its key and value
+pub fn parse_env_var(env_var: &str) -> Result<(&str, &str)> {
+    env_var
+        .split_once('=')
+        .filter(|(key, _)| !key.is_empty())
+        .ok_or_else(|| anyhow!("expected `KEY=VALUE`, found `{env_var}`"))
+}
+
+/// A level of debug information, as set by a profile's `debug` setting
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DebugLevel {
+    None,
+    LineDirectivesOnly,
+    LineTablesOnly,
+    Limited,
+    Full,
+}
+
+impl DebugLevel {
+    /// Parses a `debug` setting, e.g., `true`, `2`, or `"line-tables-only"`
+    #[must_use]
+    pub fn parse(value: &Value) -> Option<Self> {
+        match value {
+            Value::Boolean(false) | Value::Integer(0) => Some(Self::None),
+            Value::Integer(1) => Some(Self::Limited),
+            Value::Boolean(true) | Value::Integer(2) => Some(Self::Full),
+            Value::String(s) => match s.as_str() {
+                "none" | "false" | "0" => Some(Self::None),
+                "line-directives-only" => Some(Self::LineDirectivesOnly),
+                "line-tables-only" => Some(Self::LineTablesOnly),
+                "limited" | "1" => Some(Self::Limited),
+                "full" | "true" | "2" => Some(Self::Full),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Returns true if the level includes line tables that `anchor-coverage` can read
+    ///
+    /// Line directives alone produce no compilation units through which to find the line tables.
+    #[must_use]
+    pub fn has_line_tables(self) -> bool {
+        matches!(self, Self::LineTablesOnly | Self::Limited | Self::Full)
+    }
+}
+
+impl std::fmt::Display for DebugLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::None => "none",
+            Self::LineDirectivesOnly => "line-directives-only",
+            Self::LineTablesOnly => "line-tables-only",
+            Self::Limited => "limited",
+            Self::Full => "full",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// A program package's effective debug settings in coverage builds, and where they come from
+#[derive(Debug)]
+pub struct ProgramDebug {
+    pub package: String,
+    /// The name of the program's `.so` and `.debug` files, without extension
+    pub lib_name: String,
+    pub debug: DebugLevel,
+    pub debug_source: String,
+    /// The `strip` setting, if it removes debug information
+    pub strip: Option<String>,
+    pub strip_source: String,
+}
+
+impl ProgramDebug {
+    /// Returns true if the program's debug file should have line tables
+    #[must_use]
+    pub fn has_line_tables(&self) -> bool {
+        self.debug.has_line_tables() && self.strip.is_none()
+    }
+
+    /// Returns why the program's debug file will lack line tables, if it will
+    #[must_use]
+    pub fn problem(&self) -> Option<String> {
+        if !self.debug.has_line_tables() {
+            return Some(format!(
+                "`{}` is built with `debug = \"{}\"` (from {})",
+                self.package, self.debug, self.debug_source
+            ));
+        }
+        if let Some(strip) = &self.strip {
+            return Some(format!(
+                "`{}` is built with `strip = {strip}` (from {})",
+                self.package, self.strip_source
+            ));
+        }
+        None
+    }
+}
+
+/// Resolves the debug settings of each program package, i.e., each package with a `cdylib`
+/// target, in the workspace at `root`, as they would be in a coverage build
+///
+/// A coverage build sets `CARGO_PROFILE_RELEASE_DEBUG`, which takes precedence over the
+/// `[profile.release]` tables of `Cargo.toml` and `.cargo/config.toml` files. So only
+/// `[profile.release.package]` overrides in those files can change a program's `debug` setting.
+/// The `strip` setting is resolved the same way, but starting from `[profile.release]`.
+pub fn program_debug_settings(root: &Path) -> Result<Vec<ProgramDebug>> {
+    let metadata = MetadataCommand::new().current_dir(root).no_deps().exec()?;
+
+    let manifest_path = metadata.workspace_root.join("Cargo.toml");
+    let mut programs = Vec::new();
+    for package in &metadata.packages {
+        let Some(target) = package
+            .targets
+            .iter()
+            .find(|target| target.crate_types.contains(&CrateType::CDyLib))
+        else {
+            continue;
+        };
+        // smoelius: Profiles are read from the workspace's root manifest, and from the
+        // configuration files of the directory in which the package is built.
+        let mut profiles = Vec::new();
+        if let Some(profile) = release_profile(manifest_path.as_std_path())? {
+            profiles.push(profile);
+        }
+        for config_path in config_paths(package.manifest_path.parent().unwrap().as_std_path()) {
+            if let Some(profile) = release_profile(&config_path)? {
+                profiles.push(profile);
+            }
+        }
+
+        let package_name = package.name.to_string();
+        let lookup = |key: &str, base: bool| {
+            resolve(&profiles, &["package", &package_name, key])
+                .or_else(|| resolve(&profiles, &["package", "*", key]))
+                .or_else(|| base.then(|| resolve(&profiles, &[key])).flatten())
+        };
+
+        let (debug, debug_source) = match lookup("debug", false) {
+            Some((value, source)) => (
+                DebugLevel::parse(value)
+                    .ok_or_else(|| anyhow!("invalid `debug` setting `{value}` in {source}"))?,
+                source,
+            ),
+            None => (
+                DebugLevel::Full,
+                String::from("`CARGO_PROFILE_RELEASE_DEBUG`, set by coverage builds"),
+            ),
+        };
+        let (strip, strip_source) = match lookup("strip", true) {
+            Some((value, source)) if strips_debug_info(value) => (Some(value.to_string()), source),
+            _ => (None, String::new()),
+        };
+
+        programs.push(ProgramDebug {
+            package: package_name,
+            lib_name: target.name.replace('-', "_"),
+            debug,
+            debug_source,
+            strip,
+            strip_source,
+        });
+    }
+    programs.sort_by(|left, right| left.lib_name.cmp(&right.lib_name));
+    Ok(programs)
+}
+
+/// A `[profile.release]` table and a description of where it was read from
+struct Profile {
+    table: Table,
+    source: String,
+}
+
+fn release_profile(path: &Path) -> Result<Option<Profile>> {
+    let Ok(contents) = read_to_string(path) else {
+        return Ok(None);
+    };
+    let table = contents
+        .parse::<Table>()
+        .with_context(|| format!("failed to parse `{}`", path.display()))?;
+    Ok(table
+        .get("profile")
+        .and_then(Value::as_table)
+        .and_then(|table| table.get("release"))
+        .and_then(Value::as_table)
+        .map(|table| Profile {
+            table: table.clone(),
+            source: format!("`{}`", path.display()),
+        }))
+}
+
+/// Returns the Cargo configuration files that apply in `dir`, from lowest to highest precedence
+fn config_paths(dir: &Path) -> Vec<PathBuf> {
+    let cargo_home = var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| home_dir().map(|home| home.join(".cargo")));
+    let mut dirs = dir
+        .ancestors()
+        .map(|ancestor| ancestor.join(".cargo"))
+        .filter(|dir| Some(dir) != cargo_home.as_ref())
+        .collect::<Vec<_>>();
+    dirs.extend(cargo_home);
+    dirs.reverse();
+    dirs.into_iter()
+        .filter_map(|dir| {
+            ["config.toml", "config"]
+                .into_iter()
+                .map(|file_name| dir.join(file_name))
+                .find(|path| path.is_file())
+        })
+        .collect()
+}
+
+/// Returns the value at `keys` in the highest-precedence profile that has one, along with a
+/// description of where it was set
+fn resolve<'a>(profiles: &'a [Profile], keys: &[&str]) -> Option<(&'a Value, String)> {
+    profiles.iter().rev().find_map(|profile| {
+        let (last, init) = keys.split_last()?;
+        let mut table = &profile.table;
+        for key in init {
+            table = table.get(*key)?.as_table()?;
+        }
+        let value = table.get(*last)?;
+        let mut path = String::from("profile.release");
+        for key in init {
+            write!(path, ".{}", quote_key(key)).unwrap();
+        }
+        Some((value, format!("`[{path}]` in {}", profile.source)))
+    })
+}
+
+fn quote_key(key: &str) -> String {
+    if key
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        key.to_owned()
+    } else {
+        format!("\"{key}\"")
+    }
+}
+
+fn strips_debug_info(value: &Value) -> bool {
+    match value {
+        Value::Boolean(strip) => *strip,
+        Value::String(s) => s == "debuginfo" || s == "symbols",
+        _ => false,
+    }
+}
+
+/// Returns a description of each program whose debug file in `deploy_dir` is missing or has no
+/// line tables
+pub fn debug_file_problems(programs: &[ProgramDebug], deploy_dir: &Path) -> Result<Vec<String>> {
+    let mut problems = Vec::new();
+    for program in programs {
+        let debug_path = deploy_dir.join(&program.lib_name).with_extension("debug");
+        let mut problem = if !debug_path.try_exists()? {
+            format!(
+                "`{}` has no debug file at `{}`",
+                program.package,
+                debug_path.display()
+            )
+        } else if !has_line_tables(&debug_path)? {
+            format!(
+                "`{}`'s debug file `{}` has no line tables",
+                program.package,
+                debug_path.display()
+            )
+        } else {
+            continue;
+        };
+        if let Some(reason) = program.problem() {
+            write!(problem, "; {reason}").unwrap();
+        }
+        problems.push(problem);
+    }
+    Ok(problems)
+}
+
+/// The file, in the coverage target directory, recording the fingerprint of the last coverage
+/// build and the artifacts it wrote
+pub const BUILD_STAMP_FILENAME: &str = "build.stamp";
+
+/// Returns a fingerprint of the inputs to a coverage build of the workspace at `root` with
+/// `settings`
+///
+/// The inputs are each workspace member's `Cargo.toml`, `build.rs`, target source files, and
+/// `src` directory; the workspace's `Cargo.toml`, `Cargo.lock`, and `Anchor.toml`; the Cargo
+/// configuration files that apply; the output of `cargo build-sbf --version`; the `CARGO_*` and
+/// `RUST*` environment variables; and the build settings themselves. Sources outside of the
+/// workspace members, e.g., path dependencies, are not included.
+pub fn build_fingerprint(root: &Path, settings: &[&str]) -> Result<String> {
+    let metadata = MetadataCommand::new().current_dir(root).no_deps().exec()?;
+    let workspace_root = metadata.workspace_root.as_std_path();
+
+    let mut paths = ["Cargo.toml", "Cargo.lock", "Anchor.toml"]
+        .into_iter()
+        .map(|file_name| workspace_root.join(file_name))
+        .chain(config_paths(root))
+        .filter(|path| path.is_file())
+        .collect::<Vec<_>>();
+    for package in &metadata.packages {
+        let package_dir = package.manifest_path.parent().unwrap().as_std_path();
+        paths.push(package.manifest_path.clone().into_std_path_buf());
+        paths.extend(
+            package
+                .targets
+                .iter()
+                .map(|target| target.src_path.clone().into_std_path_buf()),
+        );
+        let build_rs = package_dir.join("build.rs");
+        if build_rs.is_file() {
+            paths.push(build_rs);
+        }
+        let src_dir = package_dir.join("src");
+        if src_dir.is_dir() {
+            collect_files(&src_dir, &mut paths)?;
+        }
+    }
+    paths.sort();
+    paths.dedup();
+
+    let mut env_vars = vars_os()
+        .filter(|(key, _)| {
+            let key = key.to_string_lossy();
+            key.starts_with("CARGO_") || key.starts_with("RUST")
+        })
+        .collect::<Vec<_>>();
+    env_vars.sort();
+
+    let mut fingerprint = Fingerprint::default();
+    fingerprint.update(env!("CARGO_PKG_VERSION").as_bytes());
+    fingerprint.update(&cargo_build_sbf_version());
+    for (key, value) in env_vars {
+        fingerprint.update(key.as_encoded_bytes());
+        fingerprint.update(value.as_encoded_bytes());
+    }
+    for setting in settings {
+        fingerprint.update(setting.as_bytes());
+    }
+    for path in paths {
+        fingerprint.update(path.as_os_str().as_encoded_bytes());
+        fingerprint
+            .update(&read(&path).with_context(|| format!("failed to read `{}`", path.display()))?);
+    }
+    Ok(format!("{:016x}", fingerprint.0))
+}
+
+/// A 64-bit FNV-1a hash
+///
+/// Unlike [`std::hash::DefaultHasher`], its output does not change between Rust releases, so it
+/// can be stored on disk.
+struct Fingerprint(u64);
+
+impl Default for Fingerprint {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Fingerprint {
+    /// Hashes `bytes`, preceded by their length, so that adjacent inputs cannot run together
+    fn update(&mut self, bytes: &[u8]) {
+        for &byte in (bytes.len() as u64).to_le_bytes().iter().chain(bytes) {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+/// Returns the output of `cargo build-sbf --version`, or nothing if it cannot be run
+fn cargo_build_sbf_version() -> Vec<u8> {
+    Command::new("cargo")
+        .args(["build-sbf", "--version"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| output.stdout)
+        .unwrap_or_default()
+}
+
+fn collect_files(dir: &Path, paths: &mut Vec<PathBuf>) -> Result<()> {
+    for result in read_dir(dir)? {
+        let entry = result?;
+        let path = entry.path();
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            collect_files(&path, paths)?;
+        } else {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// The contents of a build stamp
+#[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct BuildStamp {
+    fingerprint: String,
+    /// The `.so` and `.debug` files in `target/coverage/deploy` after the build, by file name
+    artifacts: BTreeMap<String, Artifact>,
+}
+
+/// A file's size and modification time, in nanoseconds since the Unix epoch
+#[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct Artifact {
+    len: u64,
+    modified: u64,
+}
+
+/// Returns the `.so` and `.debug` files in `deploy_dir`
+fn artifacts(deploy_dir: &Path) -> Result<BTreeMap<String, Artifact>> {
+    let mut artifacts = BTreeMap::new();
+    if !deploy_dir.is_dir() {
+        return Ok(artifacts);
+    }
+    for path in files_with_extension(deploy_dir, "so")?
+        .into_iter()
+        .chain(files_with_extension(deploy_dir, "debug")?)
+    {
+        let metadata = metadata(&path)?;
+        let modified = metadata.modified()?.duration_since(UNIX_EPOCH)?.as_nanos();
+        artifacts.insert(
+            path.file_name().unwrap().to_string_lossy().into_owned(),
+            Artifact {
+                len: metadata.len(),
+                modified: u64::try_from(modified)?,
+            },
+        );
+    }
+    Ok(artifacts)
+}
+
+/// Returns the path of the build stamp of the workspace at `root`
+#[must_use]
+pub fn build_stamp_path(root: &Path) -> PathBuf {
+    root.join(COVERAGE_TARGET_DIR).join(BUILD_STAMP_FILENAME)
+}
+
+/// Returns true if the last coverage build of the workspace at `root` had fingerprint
+/// `fingerprint`, and the `.so` and `.debug` files in `target/coverage/deploy` are those it wrote
+pub fn build_is_fresh(root: &Path, fingerprint: &str) -> Result<bool> {
+    let Ok(contents) = read_to_string(build_stamp_path(root)) else {
+        return Ok(false);
+    };
+    let Ok(stamp) = toml::from_str::<BuildStamp>(&contents) else {
+        return Ok(false);
+    };
+    Ok(stamp.fingerprint == fingerprint
+        && stamp
+            .artifacts
+            .keys()
+            .any(|file_name| Path::new(file_name).extension() == Some(OsStr::new("debug")))
+        && stamp.artifacts == artifacts(&root.join(COVERAGE_DEPLOY_DIR))?)
+}
+
+/// Records `fingerprint` as that of the last coverage build of the workspace at `root`, along
+/// with the `.so` and `.debug` files it wrote
+pub fn write_build_stamp(root: &Path, fingerprint: &str) -> Result<()> {
+    let stamp = BuildStamp {
+        fingerprint: fingerprint.to_owned(),
+        artifacts: artifacts(&root.join(COVERAGE_DEPLOY_DIR))?,
+    };
+    let path = build_stamp_path(root);
+    create_dir_all(path.parent().unwrap())?;
+    write(path, toml::to_string(&stamp)?).map_err(Into::into)
+}
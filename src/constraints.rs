@@ -0,0 +1,163 @@
+//! Determines which `Accounts` struct fields and constraints were checked, and which constraints
+//! failed.
+//!
+//! Anchor's generated validation code is attributed by DWARF to the `Accounts` struct. A constraint
+//! is checked by a trace if any of the trace's program counters map to the constraint's span
+//! within its `#[account(...)]` attribute.
+//!
+//! Anchor checks constraints in order and returns at the first failure. So a constraint's failure
+//! path is considered exercised by a trace if:
+//!
+//! - the trace checked the constraint last among the struct's constraints,
+//! - no later field of the struct was executed after the constraint was checked,
+//! - no handler taking the struct was executed, and
+//! - if the trace's invocation is known from the validator log, the invocation failed.
+//!
+//! This is a heuristic. Anchor's constraint errors are built in `anchor-lang`, whose code is not
+//! attributed to the struct, so the failure itself cannot be observed. Without a validator log,
+//! e.g., a trace that fails in an unrelated CPI after checking the struct's constraints is counted
+//! as a failure of the last constraint checked.
+
+use crate::{
+    instructions::ExecutedHandlers,
+    report::{AccountsReport, ConstraintReport, FieldReport},
+    source::{accounts_structs, AccountsStruct},
+    Dwarf, Entry, Trace,
+};
+use std::path::PathBuf;
+
+pub(crate) fn accounts_reports(
+    dwarf: &Dwarf,
+    sources: &[(&str, &str)],
+    handler_contexts: &[(String, String)],
+    traces: &[&Trace<'_>],
+    executed_handlers: &ExecutedHandlers,
+) -> Vec<AccountsReport> {
+    let mut accounts_reports = Vec::new();
+
+    for &(file, contents) in sources {
+        for accounts_struct in accounts_structs(contents) {
+            let handlers = handler_contexts
+                .iter()
+                .filter_map(|(handler, accounts)| {
+                    (accounts == &accounts_struct.name).then_some(handler)
+                })
+                .collect::<Vec<_>>();
+
+            let mut report = empty_accounts_report(file, &accounts_struct, !handlers.is_empty());
+
+            for (trace, executed) in traces.iter().zip(executed_handlers) {
+                let entries = trace
+                    .vaddrs
+                    .iter()
+                    .map(|vaddr| &dwarf.vaddr_entry_map[vaddr])
+                    .filter(|entry| entry.file == file)
+                    .collect::<Vec<_>>();
+
+                let handler_executed = handlers.iter().any(|handler| executed.contains(*handler));
+                // smoelius: If the validator log says how the invocation ended, only a failed
+                // invocation can have failed a constraint.
+                let invocation_failed = trace
+                    .invocation
+                    .as_ref()
+                    .is_none_or(|invocation| invocation.success == Some(false));
+
+                record_trace(
+                    &accounts_struct,
+                    &mut report,
+                    &entries,
+                    !handlers.is_empty() && !handler_executed && invocation_failed,
+                );
+            }
+
+            accounts_reports.push(report);
+        }
+    }
+
+    accounts_reports
+}
+
+pub(crate) fn empty_accounts_report(
+    file: &str,
+    accounts_struct: &AccountsStruct,
+    failures_known: bool,
+) -> AccountsReport {
+    AccountsReport {
+        name: accounts_struct.name.clone(),
+        file: PathBuf::from(file),
+        line: accounts_struct.line,
+        fields: accounts_struct
+            .fields
+            .iter()
+            .map(|field| FieldReport {
+                name: field.name.clone(),
+                line: field.line,
+                executions: 0,
+                constraints: field
+                    .constraints
+                    .iter()
+                    .map(|constraint| ConstraintReport {
+                        constraint: constraint.text.clone(),
+                        line: constraint.span.start.0,
+                        column: constraint.span.start.1,
+                        executions: 0,
+                        failures: failures_known.then_some(0),
+                    })
+                    .collect(),
+            })
+            .collect(),
+    }
+}
+
+/// Updates `report` with the fields and constraints checked by one trace
+///
+/// `entries` are the trace's entries within the file containing `accounts_struct`. If `failed` is
+/// true, the last constraint checked is considered to have failed, unless a later field of the
+/// struct was executed after it, i.e., unless Anchor went on to validate other fields.
+pub(crate) fn record_trace(
+    accounts_struct: &AccountsStruct,
+    report: &mut AccountsReport,
+    entries: &[&Entry<'_>],
+    failed: bool,
+) {
+    // smoelius: (position in `entries`, field index, constraint index)
+    let mut last_checked: Option<(usize, usize, usize)> = None;
+
+    for (field_index, field) in accounts_struct.fields.iter().enumerate() {
+        let field_report = &mut report.fields[field_index];
+
+        if entries
+            .iter()
+            .any(|entry| field.lines.contains(&entry.line))
+        {
+            field_report.executions += 1;
+        }
+
+        for (constraint_index, constraint) in field.constraints.iter().enumerate() {
+            let Some(position) = entries
+                .iter()
+                .rposition(|entry| constraint.span.contains(entry.line, entry.column))
+            else {
+                continue;
+            };
+            field_report.constraints[constraint_index].executions += 1;
+            if last_checked.is_none_or(|(last_position, _, _)| last_position < position) {
+                last_checked = Some((position, field_index, constraint_index));
+            }
+        }
+    }
+
+    if failed
+        && let Some((position, field_index, constraint_index)) = last_checked
+        && !entries[position + 1..].iter().any(|entry| {
+            accounts_struct.fields[field_index + 1..]
+                .iter()
+                .any(|field| field.lines.contains(&entry.line))
+        })
+    {
+        let failures = &mut report.fields[field_index].constraints[constraint_index].failures;
+        if let Some(failures) = failures {
+            *failures += 1;
+        }
+    }
+}
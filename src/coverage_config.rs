@@ -0,0 +1,186 @@
+//! Settings read from the `[coverage]` table of `Anchor.toml`, or from `anchor-coverage.toml`.
+//!
+//! `anchor-coverage.toml` lives next to `Anchor.toml` and holds the same settings as the
+//! `[coverage]` table, but at the top level. Relative paths are resolved against the workspace
+//! root, i.e., the directory containing `Anchor.toml`. Command line options override the settings.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    env::current_dir,
+    fs::read_to_string,
+    path::{Path, PathBuf},
+};
+
+pub const COVERAGE_CONFIG_FILENAME: &str = "anchor-coverage.toml";
+
+/// The trace directory used if none is configured, relative to the workspace root
+pub const DEFAULT_TRACE_DIR: &str = "sbf_trace_dir";
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CoverageConfig {
+    /// Arguments passed to `anchor test`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub anchor_test_args: Vec<String>,
+    /// Arguments passed to `cargo test` by `cargo-test`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cargo_test_args: Vec<String>,
+    /// Command run by `native` to exercise the programs, e.g., `["cargo", "test"]`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub native_test_command: Vec<String>,
+    /// Programs to build, by package or library name; if empty, all are built
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub programs: Vec<String>,
+    /// Arguments passed to `cargo build-sbf`, in addition to `--debug`, e.g., `--features`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub build_sbf_args: Vec<String>,
+    /// `KEY=VALUE` environment variables set for `cargo build-sbf`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub build_env_vars: Vec<String>,
+    /// Skip generating IDLs, and use those already in `target/idl`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub no_idl: bool,
+    /// Skip building, and use the debug files already in `target/coverage/deploy`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub skip_build: bool,
+    /// Optimization level of coverage builds, overriding `[profile.release]`'s `opt-level`
+    pub opt_level: Option<String>,
+    /// Directory to which program counters files are written, and from which they are read
+    pub trace_dir: Option<PathBuf>,
+    /// Directory containing the debug files used by `report`
+    pub debug_dir: Option<PathBuf>,
+    /// Validator log with which to label program counters files
+    pub validator_log: Option<PathBuf>,
+    /// `FROM=TO` rules applied to source file paths by `merge`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub remap: Vec<String>,
+}
+
+/// A [`CoverageConfig`] and where it was read from
+#[derive(Debug, Default)]
+pub struct LoadedCoverageConfig {
+    /// The file the settings were read from, or `None` if there was none
+    pub path: Option<PathBuf>,
+    pub config: CoverageConfig,
+}
+
+impl CoverageConfig {
+    /// Resolves relative paths against `root`
+    fn resolve(mut self, root: &Path) -> Self {
+        for path in [
+            &mut self.trace_dir,
+            &mut self.debug_dir,
+            &mut self.validator_log,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *path = root.join(&*path);
+        }
+        self
+    }
+
+    /// Returns the settings as a `[coverage]` table
+    pub fn to_toml(&self) -> Result<String> {
+        #[derive(Serialize)]
+        struct Wrapper<'a> {
+            coverage: &'a CoverageConfig,
+        }
+        toml::to_string(&Wrapper { coverage: self }).map_err(Into::into)
+    }
+
+    /// Returns the configured trace directory, or the default one under `root`
+    #[must_use]
+    pub fn trace_dir(&self, root: &Path) -> PathBuf {
+        self.trace_dir
+            .clone()
+            .unwrap_or_else(|| root.join(DEFAULT_TRACE_DIR))
+    }
+}
+
+/// Returns the Anchor workspace root, i.e., the directory containing `Anchor.toml`, as found by
+/// Anchor's `Config::discover`, or the current directory if there is no `Anchor.toml`
+#[cfg(feature = "__anchor_cli")]
+pub fn workspace_root() -> Result<PathBuf> {
+    let config = crate::config::Config::discover(&crate::ConfigOverride::default())?;
+    match config.as_ref().and_then(|config| config.path().parent()) {
+        Some(root) => Ok(root.to_path_buf()),
+        None => current_dir().map_err(Into::into),
+    }
+}
+
+/// Returns the Anchor workspace root, i.e., the nearest ancestor of the current directory
+/// containing `Anchor.toml`, or the current directory if there is none
+#[cfg(not(feature = "__anchor_cli"))]
+pub fn workspace_root() -> Result<PathBuf> {
+    let current_dir = current_dir()?;
+    Ok(current_dir
+        .ancestors()
+        .find(|dir| dir.join("Anchor.toml").is_file())
+        .unwrap_or(&current_dir)
+        .to_path_buf())
+}
+
+/// Reads the coverage settings from `Anchor.toml` or `anchor-coverage.toml` in `root`
+pub fn load_coverage_config(root: &Path) -> Result<LoadedCoverageConfig> {
+    let anchor_toml = Some(root.join("Anchor.toml")).filter(|path| path.is_file());
+
+    let from_anchor_toml = anchor_toml
+        .as_deref()
+        .map(anchor_toml_coverage_config)
+        .transpose()?
+        .flatten();
+
+    let path = root.join(COVERAGE_CONFIG_FILENAME);
+    if path.try_exists()? {
+        if from_anchor_toml.is_some() {
+            bail!(
+                "found both `{}` and a `[coverage]` table in `Anchor.toml`; please use only one",
+                path.display()
+            );
+        }
+        let contents = read_to_string(&path)?;
+        let config = toml::from_str::<CoverageConfig>(&contents)
+            .with_context(|| format!("failed to parse `{}`", path.display()))?;
+        return Ok(LoadedCoverageConfig {
+            config: config.resolve(root),
+            path: Some(path),
+        });
+    }
+
+    Ok(match (anchor_toml, from_anchor_toml) {
+        (Some(anchor_toml), Some(config)) => LoadedCoverageConfig {
+            config: config.resolve(root),
+            path: Some(anchor_toml),
+        },
+        _ => LoadedCoverageConfig::default(),
+    })
+}
+
+#[cfg(feature = "__anchor_cli")]
+fn anchor_toml_coverage_config(anchor_toml: &Path) -> Result<Option<CoverageConfig>> {
+    let contents = read_to_string(anchor_toml)?;
+    let config = contents
+        .parse::<crate::config::Config>()
+        .with_context(|| format!("failed to parse `{}`", anchor_toml.display()))?;
+    Ok(config.coverage)
+}
+
+#[cfg(not(feature = "__anchor_cli"))]
+fn anchor_toml_coverage_config(anchor_toml: &Path) -> Result<Option<CoverageConfig>> {
+    let contents = read_to_string(anchor_toml)?;
+    let mut table = contents
+        .parse::<toml::Table>()
+        .with_context(|| format!("failed to parse `{}`", anchor_toml.display()))?;
+    table
+        .remove("coverage")
+        .map(toml::Value::try_into::<CoverageConfig>)
+        .transpose()
+        .with_context(|| {
+            format!(
+                "failed to parse `[coverage]` in `{}`",
+                anchor_toml.display()
+            )
+        })
+}
@@ -0,0 +1,113 @@
+//! Puts the programs of a coverage build where `anchor test` loads them from.
+//!
+//! Coverage builds write their `.so` and `.debug` files to [`COVERAGE_DEPLOY_DIR`], but Anchor
+//! loads the programs of a workspace only from `target/deploy`. So, while `anchor test` runs, each
+//! coverage `.so` file is copied to `target/deploy`, after the release `.so` file it replaces, if
+//! any, is moved to [`RELEASE_BACKUP_DIR`]. When the returned guard is dropped, the copies are
+//! removed and the release files are moved back, so they are left as they were.
+//!
+//! If a run is interrupted before the release files are moved back, the next run moves them back
+//! first, unless `target/deploy` has since been rebuilt.
+
+use crate::{
+    build::{COVERAGE_DEPLOY_DIR, DEPLOY_DIR},
+    util::files_with_extension,
+};
+use anyhow::Result;
+use std::{
+    fs::{copy, create_dir_all, read, remove_file, rename},
+    path::{Path, PathBuf},
+};
+
+/// The directory to which release `.so` files are moved while coverage `.so` files replace them,
+/// relative to the workspace root
+pub const RELEASE_BACKUP_DIR: &str = "target/coverage/release";
+
+/// Coverage `.so` files copied to `target/deploy`, removed when dropped
+#[derive(Debug)]
+pub struct StagedPrograms {
+    /// For each copy, the release file it replaced, if any
+    staged: Vec<(PathBuf, Option<PathBuf>)>,
+}
+
+impl Drop for StagedPrograms {
+    fn drop(&mut self) {
+        for (path, backup) in &self.staged {
+            if let Err(error) = restore(path, backup.as_deref()) {
+                eprintln!("Warning: failed to restore `{}`: {error}", path.display());
+            }
+        }
+    }
+}
+
+fn restore(path: &Path, backup: Option<&Path>) -> Result<()> {
+    if path.try_exists()? {
+        remove_file(path)?;
+    }
+    if let Some(backup) = backup {
+        rename(backup, path)?;
+    }
+    Ok(())
+}
+
+/// Copies the `.so` files of the last coverage build of the workspace at `root` to
+/// `target/deploy`, moving aside any release `.so` files they replace
+pub fn stage_programs(root: &Path) -> Result<StagedPrograms> {
+    let coverage_deploy_dir = root.join(COVERAGE_DEPLOY_DIR);
+    let deploy_dir = root.join(DEPLOY_DIR);
+    let backup_dir = root.join(RELEASE_BACKUP_DIR);
+
+    restore_interrupted(&coverage_deploy_dir, &deploy_dir, &backup_dir)?;
+
+    create_dir_all(&deploy_dir)?;
+    create_dir_all(&backup_dir)?;
+
+    let mut staged = StagedPrograms { staged: Vec::new() };
+    for coverage_path in files_with_extension(&coverage_deploy_dir, "so")? {
+        let file_name = coverage_path.file_name().unwrap();
+        let path = deploy_dir.join(file_name);
+        let backup = if path.try_exists()? {
+            let backup = backup_dir.join(file_name);
+            rename(&path, &backup)?;
+            Some(backup)
+        } else {
+            None
+        };
+        // smoelius: Record the file before copying, so that a failed copy still moves the release
+        // file back.
+        staged.staged.push((path.clone(), backup));
+        copy(&coverage_path, &path)?;
+    }
+    Ok(staged)
+}
+
+/// Moves back the release files of a run that was interrupted while they were moved aside
+///
+/// A file in `target/deploy` that differs from the coverage file of the same name was rebuilt
+/// since, in which case the moved file is stale and is deleted instead.
+fn restore_interrupted(
+    coverage_deploy_dir: &Path,
+    deploy_dir: &Path,
+    backup_dir: &Path,
+) -> Result<()> {
+    if !backup_dir.is_dir() {
+        return Ok(());
+    }
+    for backup in files_with_extension(backup_dir, "so")? {
+        let file_name = backup.file_name().unwrap();
+        let path = deploy_dir.join(file_name);
+        let coverage_path = coverage_deploy_dir.join(file_name);
+        if !path.try_exists()?
+            || (coverage_path.try_exists()? && read(&path)? == read(&coverage_path)?)
+        {
+            eprintln!(
+                "Restoring `{}`, which an interrupted run left replaced",
+                path.display()
+            );
+            rename(&backup, &path)?;
+        } else {
+            remove_file(&backup)?;
+        }
+    }
+    Ok(())
+}
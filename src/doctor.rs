@@ -0,0 +1,173 @@
+//! Building blocks for the `doctor` subcommand, which checks the toolchain and workspace before
+//! any tests are run.
+
+use addr2line::Loader;
+use anyhow::{anyhow, Result};
+use cargo_metadata::{CrateType, MetadataCommand};
+use std::{fmt::Write, path::Path};
+use toml::{Table, Value};
+
+/// The Agave release the patched tools are built from, e.g., `v3.0.8`
+pub const AGAVE_TAG: &str = include_str!("../agave_tag.txt");
+
+/// The outcome of a check
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+/// A check's outcome, what was found, and how to fix any problem
+#[derive(Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub message: String,
+    pub suggestion: Option<String>,
+}
+
+impl Check {
+    #[must_use]
+    pub fn pass(name: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Pass,
+            message: message.into(),
+            suggestion: None,
+        }
+    }
+
+    #[must_use]
+    pub fn warn(
+        name: &'static str,
+        message: impl Into<String>,
+        suggestion: impl Into<String>,
+    ) -> Self {
+        Self {
+            name,
+            status: Status::Warn,
+            message: message.into(),
+            suggestion: Some(suggestion.into()),
+        }
+    }
+
+    #[must_use]
+    pub fn fail(
+        name: &'static str,
+        message: impl Into<String>,
+        suggestion: impl Into<String>,
+    ) -> Self {
+        Self {
+            name,
+            status: Status::Fail,
+            message: message.into(),
+            suggestion: Some(suggestion.into()),
+        }
+    }
+}
+
+/// Returns the Agave version the patched tools are built from, e.g., `3.0.8`
+#[must_use]
+pub fn agave_version() -> &'static str {
+    let tag = AGAVE_TAG.trim();
+    tag.strip_prefix('v').unwrap_or(tag)
+}
+
+/// Returns one line per check, each followed by a suggested fix if the check did not pass
+#[must_use]
+pub fn render(checks: &[Check]) -> String {
+    let mut output = String::new();
+    for check in checks {
+        let label = match check.status {
+            Status::Pass => "PASS",
+            Status::Warn => "WARN",
+            Status::Fail => "FAIL",
+        };
+        writeln!(output, "[{label}] {}: {}", check.name, check.message).unwrap();
+        if let Some(suggestion) = &check.suggestion {
+            writeln!(output, "       Fix: {suggestion}").unwrap();
+        }
+    }
+    output
+}
+
+/// Returns the version of `package` recorded in the `Cargo.lock` contents `contents`
+///
+/// If the lockfile records several versions of `package`, the greatest is returned.
+pub fn lockfile_version(contents: &str, package: &str) -> Result<Option<String>> {
+    let table = contents.parse::<Table>()?;
+    let mut versions = table
+        .get("package")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_table)
+        .filter(|table| table.get("name").and_then(Value::as_str) == Some(package))
+        .filter_map(|table| table.get("version").and_then(Value::as_str))
+        .map(|version| {
+            let parts = version
+                .split(['.', '-'])
+                .map(|part| part.parse::<u64>().unwrap_or_default())
+                .collect::<Vec<_>>();
+            (parts, version)
+        })
+        .collect::<Vec<_>>();
+    versions.sort();
+    Ok(versions.pop().map(|(_, version)| version.to_owned()))
+}
+
+/// Returns true if the Cargo.toml contents `contents` enable `overflow-checks` in the release
+/// profile
+///
+/// `anchor build` refuses to build a workspace whose root Cargo.toml does not.
+pub fn overflow_checks_enabled(contents: &str) -> Result<bool> {
+    let table = contents.parse::<Table>()?;
+    Ok(table
+        .get("profile")
+        .and_then(Value::as_table)
+        .and_then(|table| table.get("release"))
+        .and_then(Value::as_table)
+        .and_then(|table| table.get("overflow-checks"))
+        .and_then(Value::as_bool)
+        .unwrap_or_default())
+}
+
+/// Returns the names of the programs in the workspace at `root` that depend on `solana-program`
+/// directly
+///
+/// `anchor build` warns about such programs, because their `solana-program` may conflict with
+/// `anchor-lang`'s.
+pub fn solana_program_dependents(root: &Path) -> Result<Vec<String>> {
+    let metadata = MetadataCommand::new().current_dir(root).no_deps().exec()?;
+    Ok(metadata
+        .packages
+        .iter()
+        .filter(|package| {
+            package
+                .targets
+                .iter()
+                .any(|target| target.crate_types.contains(&CrateType::CDyLib))
+                && package
+                    .dependencies
+                    .iter()
+                    .any(|dependency| dependency.name == "solana-program")
+        })
+        .map(|package| package.name.to_string())
+        .collect())
+}
+
+/// Returns true if the debug file at `debug_path` has a `.debug_line` section, i.e., DWARF line
+/// tables
+pub fn has_line_tables(debug_path: &Path) -> Result<bool> {
+    let loader = Loader::new(debug_path).map_err(|error| {
+        anyhow!(
+            "failed to build loader for {}: {}",
+            debug_path.display(),
+            error
+        )
+    })?;
+    Ok(loader
+        .get_section_range(b".debug_line")
+        .is_some_and(|range| range.begin < range.end))
+}
@@ -0,0 +1,197 @@
+//! Determines which custom errors were triggered, and which error-returning macro invocations took
+//! their error paths.
+//!
+//! An `#[error_code]` variant is triggered by a trace if any of the trace's program counters map to
+//! the variant (e.g., through the generated `name` or `Display` implementations), or if a site
+//! naming the variant took its error path.
+//!
+//! `err!` and `error!` always produce errors, so their sites take their error paths whenever they
+//! are executed. A `require!`-like macro takes its error path if, after executing the macro, the
+//! next location executed is within the error's `#[error_code]` enum. If the error is one of
+//! Anchor's built-in errors, whether the error path was taken cannot be determined.
+
+use crate::{
+    idl::Idl,
+    report::{ErrorReport, ErrorSiteReport},
+    source::{error_enums, error_sites, ErrorEnum, ErrorSite, ErrorVariant},
+    Dwarf, Entry, Trace,
+};
+use std::path::PathBuf;
+
+pub(crate) fn error_reports(
+    dwarf: &Dwarf,
+    sources: &[(&str, &str)],
+    idl: Option<&Idl>,
+    traces: &[&Trace<'_>],
+) -> (Vec<ErrorReport>, Vec<ErrorSiteReport>) {
+    let error_enums = sources
+        .iter()
+        .flat_map(|&(file, contents)| {
+            error_enums(contents)
+                .into_iter()
+                .map(move |error_enum| (file, error_enum))
+        })
+        .collect::<Vec<_>>();
+
+    let error_sites = sources
+        .iter()
+        .flat_map(|&(file, contents)| {
+            error_sites(contents)
+                .into_iter()
+                .map(move |error_site| (file, error_site))
+        })
+        .collect::<Vec<_>>();
+
+    let mut error_reports = error_enums
+        .iter()
+        .flat_map(|(file, error_enum)| {
+            error_enum.variants.iter().map(move |variant| ErrorReport {
+                name: variant.name.clone(),
+                code: idl.and_then(|idl| {
+                    idl.errors
+                        .iter()
+                        .find(|error| error.name == variant.name)
+                        .map(|error| error.code)
+                }),
+                file: PathBuf::from(file),
+                line: variant.line,
+                executions: 0,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut error_site_reports = error_sites
+        .iter()
+        .map(|(file, error_site)| ErrorSiteReport {
+            macro_name: error_site.macro_name.clone(),
+            error: error_site.error.clone(),
+            file: PathBuf::from(file),
+            line: *error_site.lines.start(),
+            executions: 0,
+            error_path_executions: (is_unconditional(error_site)
+                || resolve(&error_enums, error_site).is_some())
+            .then_some(0),
+        })
+        .collect::<Vec<_>>();
+
+    for trace in traces {
+        let entries = trace
+            .vaddrs
+            .iter()
+            .map(|vaddr| &dwarf.vaddr_entry_map[vaddr])
+            .collect::<Vec<_>>();
+
+        let mut triggered = vec![false; error_reports.len()];
+
+        for ((file, error_site), report) in error_sites.iter().zip(&mut error_site_reports) {
+            let positions = entries
+                .iter()
+                .enumerate()
+                .filter_map(|(position, entry)| {
+                    within(entry, file, &error_site.lines).then_some(position)
+                })
+                .collect::<Vec<_>>();
+            if positions.is_empty() {
+                continue;
+            }
+            report.executions += 1;
+
+            let resolved = resolve(&error_enums, error_site);
+            if !error_path_executed(&entries, &positions, file, error_site, resolved) {
+                continue;
+            }
+            if let Some(error_path_executions) = &mut report.error_path_executions {
+                *error_path_executions += 1;
+            }
+            if let Some((_, _, variant)) = resolved {
+                let index = variant_index(&error_enums, variant);
+                triggered[index] = true;
+            }
+        }
+
+        for (index, (file, variant)) in variants(&error_enums).enumerate() {
+            if entries
+                .iter()
+                .any(|entry| within(entry, file, &variant.lines))
+            {
+                triggered[index] = true;
+            }
+        }
+
+        for (report, triggered) in error_reports.iter_mut().zip(triggered) {
+            report.executions += usize::from(triggered);
+        }
+    }
+
+    (error_reports, error_site_reports)
+}
+
+fn is_unconditional(error_site: &ErrorSite) -> bool {
+    matches!(error_site.macro_name.as_str(), "err" | "error")
+}
+
+/// Returns true if `error_site`, executed at `positions` within `entries`, took its error path
+fn error_path_executed(
+    entries: &[&Entry<'_>],
+    positions: &[usize],
+    file: &str,
+    error_site: &ErrorSite,
+    resolved: Option<(&str, &ErrorEnum, &ErrorVariant)>,
+) -> bool {
+    if is_unconditional(error_site) {
+        return true;
+    }
+    let Some((enum_file, error_enum, _)) = resolved else {
+        return false;
+    };
+    positions.iter().any(|&position| {
+        entries[position..]
+            .iter()
+            .find(|entry| !within(entry, file, &error_site.lines))
+            .is_some_and(|entry| within(entry, enum_file, &error_enum.lines))
+    })
+}
+
+/// Returns the enum and variant that `error_site`'s error argument names, if any
+///
+/// The error argument is resolved by its last two path segments, e.g., `VaultError::Unauthorized`.
+fn resolve<'a>(
+    error_enums: &'a [(&'a str, ErrorEnum)],
+    error_site: &ErrorSite,
+) -> Option<(&'a str, &'a ErrorEnum, &'a ErrorVariant)> {
+    let error = error_site.error.as_ref()?;
+    let mut segments = error.rsplit("::").map(str::trim);
+    let variant_name = segments.next()?;
+    let enum_name = segments.next();
+    error_enums
+        .iter()
+        .filter(|(_, error_enum)| enum_name.is_none_or(|enum_name| error_enum.name == enum_name))
+        .find_map(|(file, error_enum)| {
+            error_enum
+                .variants
+                .iter()
+                .find(|variant| variant.name == variant_name)
+                .map(|variant| (*file, error_enum, variant))
+        })
+}
+
+fn variants<'a>(
+    error_enums: &'a [(&'a str, ErrorEnum)],
+) -> impl Iterator<Item = (&'a str, &'a ErrorVariant)> {
+    error_enums.iter().flat_map(|(file, error_enum)| {
+        error_enum
+            .variants
+            .iter()
+            .map(move |variant| (*file, variant))
+    })
+}
+
+fn variant_index(error_enums: &[(&str, ErrorEnum)], variant: &ErrorVariant) -> usize {
+    variants(error_enums)
+        .position(|(_, other)| std::ptr::eq(other, variant))
+        .unwrap()
+}
+
+fn within(entry: &Entry<'_>, file: &str, lines: &std::ops::RangeInclusive<u32>) -> bool {
+    entry.file == file && lines.contains(&entry.line)
+}
@@ -0,0 +1,118 @@
+//! Determines which events were emitted, and by which `emit!` and `emit_cpi!` invocations.
+//!
+//! The events of a program are the `#[event]` structs in its sources, together with the events in
+//! its IDL. An invocation is considered executed by a trace if any of the trace's program counters
+//! map to the invocation's lines. An event is considered emitted by a trace if any invocation
+//! naming the event was executed.
+//!
+//! Invocations whose event cannot be determined, e.g., because the argument is a variable, are
+//! reported separately, so that they are not silently dropped.
+
+use crate::{
+    idl::Idl,
+    report::{EmitSiteReport, EventReport},
+    source::{emit_sites, event_structs, EmitSite},
+    Dwarf, Trace,
+};
+use std::path::PathBuf;
+
+pub(crate) fn event_reports(
+    dwarf: &Dwarf,
+    sources: &[(&str, &str)],
+    idl: Option<&Idl>,
+    traces: &[&Trace<'_>],
+) -> (Vec<EventReport>, Vec<EmitSiteReport>) {
+    let mut event_reports = sources
+        .iter()
+        .flat_map(|&(file, contents)| {
+            event_structs(contents)
+                .into_iter()
+                .map(move |event_struct| EventReport {
+                    name: event_struct.name,
+                    file: Some(PathBuf::from(file)),
+                    line: Some(event_struct.line),
+                    executions: 0,
+                    sites: Vec::new(),
+                })
+        })
+        .collect::<Vec<_>>();
+
+    // smoelius: Events that appear only in the IDL are likely defined in a dependency.
+    for event in idl.iter().flat_map(|idl| &idl.events) {
+        if event_reports.iter().all(|report| report.name != event.name) {
+            event_reports.push(EventReport {
+                name: event.name.clone(),
+                file: None,
+                line: None,
+                executions: 0,
+                sites: Vec::new(),
+            });
+        }
+    }
+
+    let emit_sites = sources
+        .iter()
+        .flat_map(|&(file, contents)| {
+            emit_sites(contents)
+                .into_iter()
+                .map(move |emit_site| (file, emit_site))
+        })
+        .collect::<Vec<_>>();
+
+    let executed = traces
+        .iter()
+        .map(|trace| {
+            emit_sites
+                .iter()
+                .map(|(file, emit_site)| {
+                    trace.vaddrs.iter().any(|vaddr| {
+                        let entry = &dwarf.vaddr_entry_map[vaddr];
+                        entry.file == *file && emit_site.lines.contains(&entry.line)
+                    })
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    for report in &mut event_reports {
+        let indices = emit_sites
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, emit_site))| emit_site.event.as_ref() == Some(&report.name))
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+
+        report.executions = executed
+            .iter()
+            .filter(|executed| indices.iter().any(|&index| executed[index]))
+            .count();
+
+        report.sites = indices
+            .iter()
+            .map(|&index| emit_site_report(&emit_sites, &executed, index))
+            .collect();
+    }
+
+    let unresolved_emit_sites = emit_sites
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, emit_site))| emit_site.event.is_none())
+        .map(|(index, _)| emit_site_report(&emit_sites, &executed, index))
+        .collect();
+
+    (event_reports, unresolved_emit_sites)
+}
+
+fn emit_site_report(
+    emit_sites: &[(&str, EmitSite)],
+    executed: &[Vec<bool>],
+    index: usize,
+) -> EmitSiteReport {
+    let (file, emit_site) = &emit_sites[index];
+    EmitSiteReport {
+        macro_name: emit_site.macro_name.clone(),
+        file: PathBuf::from(file),
+        line: *emit_site.lines.start(),
+        executions: executed.iter().filter(|executed| executed[index]).count(),
+    }
+}
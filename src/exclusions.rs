@@ -0,0 +1,109 @@
+//! Finds source lines excluded from coverage by in-source markers.
+//!
+//! The following markers are recognized:
+//!
+//! - `// coverage:ignore-line` excludes the line on which it appears.
+//! - `// coverage:ignore-start` and `// coverage:ignore-end` exclude the lines between them,
+//!   inclusive.
+//! - `#[coverage(off)]`, or `#[cfg_attr(coverage_nightly, coverage(off))]`, excludes the item that
+//!   follows it, e.g., a whole function.
+
+use crate::source::{skip_char_literal, skip_string, split_comment};
+use std::collections::BTreeSet;
+
+const IGNORE_LINE: &str = "coverage:ignore-line";
+const IGNORE_START: &str = "coverage:ignore-start";
+const IGNORE_END: &str = "coverage:ignore-end";
+/// The attributes that exclude an item, with whitespace removed
+const COVERAGE_OFF_ATTRIBUTES: &[&str] = &[
+    "#[coverage(off)]",
+    "#[cfg_attr(coverage_nightly,coverage(off))]",
+];
+
+/// Returns the (one-based) numbers of the lines in `contents` excluded by markers
+pub(crate) fn excluded_lines(contents: &str) -> BTreeSet<u32> {
+    let lines = contents.lines().collect::<Vec<_>>();
+    let mut excluded = BTreeSet::new();
+    let mut region_start = None;
+
+    for (index, &line) in lines.iter().enumerate() {
+        let (code, comment) = split_comment(line);
+        if comment.contains(IGNORE_START) {
+            region_start.get_or_insert(index);
+        }
+        if let Some(start) = region_start {
+            if comment.contains(IGNORE_END) {
+                excluded.extend(line_numbers(start..=index));
+                region_start = None;
+            }
+            continue;
+        }
+        if comment.contains(IGNORE_LINE) {
+            excluded.extend(line_numbers(index..=index));
+        }
+        if is_coverage_off_attribute(code) {
+            let end = item_end(&lines, index);
+            excluded.extend(line_numbers(index..=end));
+        }
+    }
+
+    // smoelius: An unterminated region extends to the end of the file.
+    if let Some(start) = region_start {
+        excluded.extend(line_numbers(start..lines.len()));
+    }
+
+    excluded
+}
+
+fn line_numbers(indices: impl Iterator<Item = usize>) -> impl Iterator<Item = u32> {
+    indices.map(|index| u32::try_from(index + 1).unwrap_or(u32::MAX))
+}
+
+fn is_coverage_off_attribute(code: &str) -> bool {
+    let code = code.split_whitespace().collect::<String>();
+    COVERAGE_OFF_ATTRIBUTES.contains(&code.as_str())
+}
+
+/// Returns the index of the last line of the item whose attribute is on line `start`
+///
+/// The item ends at the brace matching the first opening brace, or at the first semicolon outside
+/// of brackets and parentheses if one appears before any opening brace. So a semicolon in a
+/// signature, e.g., in `fn f() -> [u8; 4] {`, does not end the item.
+fn item_end(lines: &[&str], start: usize) -> usize {
+    let mut depth = 0usize;
+    let mut bracket_depth = 0usize;
+    let mut opened = false;
+    for (index, line) in lines.iter().enumerate().skip(start) {
+        let (code, _) = split_comment(line);
+        let bytes = code.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'"' => {
+                    i = skip_string(bytes, i);
+                    continue;
+                }
+                b'\'' => {
+                    i = skip_char_literal(code, i);
+                    continue;
+                }
+                b'{' => {
+                    depth += 1;
+                    opened = true;
+                }
+                b'}' => {
+                    depth = depth.saturating_sub(1);
+                    if opened && depth == 0 {
+                        return index;
+                    }
+                }
+                b'[' | b'(' => bracket_depth += 1,
+                b']' | b')' => bracket_depth = bracket_depth.saturating_sub(1),
+                b';' if !opened && bracket_depth == 0 => return index,
+                _ => {}
+            }
+            i += 1;
+        }
+    }
+    lines.len().saturating_sub(1)
+}
@@ -0,0 +1,41 @@
+//! The parts of an Anchor IDL that `anchor-coverage` uses.
+//!
+//! Anchor writes IDLs to `target/idl/<program>.json`. Only the fields needed to produce reports are
+//! deserialized; all others are ignored.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::{fs::read_to_string, path::Path};
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct Idl {
+    #[serde(default)]
+    pub instructions: Vec<Named>,
+    #[serde(default)]
+    pub errors: Vec<IdlError>,
+    #[serde(default)]
+    pub events: Vec<Named>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct Named {
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct IdlError {
+    pub code: u32,
+    pub name: String,
+}
+
+/// Reads the IDL for `program` from `idl_dir`, if one exists
+pub(crate) fn read_idl(idl_dir: &Path, program: &str) -> Result<Option<Idl>> {
+    let path = idl_dir.join(program).with_extension("json");
+    if !path.try_exists()? {
+        return Ok(None);
+    }
+    let contents = read_to_string(&path)?;
+    let idl = serde_json::from_str(&contents)
+        .with_context(|| format!("failed to parse IDL: {}", path.display()))?;
+    Ok(Some(idl))
+}
@@ -0,0 +1,80 @@
+//! Determines which of a program's IDL instructions were executed.
+//!
+//! An instruction's handler is the function of the same name in the program's `#[program]` module.
+//! A trace executes a handler if any of the trace's program counters map, through DWARF, to a frame
+//! of the handler, whether inlined or not.
+
+use crate::{idl::Idl, report::InstructionReport, Dwarf, Trace};
+use anyhow::Result;
+use std::collections::{btree_map, BTreeMap, BTreeSet};
+
+/// For each trace, the names of the handlers it executed
+pub(crate) type ExecutedHandlers = Vec<BTreeSet<String>>;
+
+pub(crate) fn executed_handlers(
+    dwarf: &Dwarf,
+    module: Option<&str>,
+    handlers: &BTreeSet<String>,
+    traces: &[&Trace<'_>],
+) -> Result<ExecutedHandlers> {
+    let mut function_names_cache = BTreeMap::<u64, Vec<String>>::new();
+    let mut executed_handlers = ExecutedHandlers::new();
+
+    for trace in traces {
+        let mut executed = BTreeSet::new();
+        for &vaddr in trace.vaddrs.iter().collect::<BTreeSet<_>>() {
+            let function_names = match function_names_cache.entry(vaddr) {
+                btree_map::Entry::Occupied(entry) => entry.into_mut(),
+                btree_map::Entry::Vacant(entry) => entry.insert(dwarf.function_names(vaddr)?),
+            };
+            for function_name in function_names.iter() {
+                executed.extend(
+                    handlers
+                        .iter()
+                        .filter(|handler| is_handler(function_name, module, handler))
+                        .cloned(),
+                );
+            }
+        }
+        executed_handlers.push(executed);
+    }
+
+    Ok(executed_handlers)
+}
+
+pub(crate) fn instruction_reports(
+    idl: &Idl,
+    module: Option<&str>,
+    executed_handlers: &ExecutedHandlers,
+) -> Vec<InstructionReport> {
+    idl.instructions
+        .iter()
+        .map(|instruction| InstructionReport {
+            name: instruction.name.clone(),
+            handler: module.map_or_else(
+                || instruction.name.clone(),
+                |module| format!("{module}::{}", instruction.name),
+            ),
+            executions: executed_handlers
+                .iter()
+                .filter(|executed| executed.contains(&instruction.name))
+                .count(),
+        })
+        .collect()
+}
+
+/// Returns true if `function_name` names the handler for `instruction`
+///
+/// If the `#[program]` module could not be found, any function named `instruction` outside of
+/// Anchor's generated `__private` module is considered the handler.
+pub(crate) fn is_handler(function_name: &str, module: Option<&str>, instruction: &str) -> bool {
+    if function_name
+        .split("::")
+        .any(|segment| segment == "__private")
+    {
+        return false;
+    }
+    let mut segments = function_name.rsplit("::");
+    segments.next() == Some(instruction)
+        && module.is_none_or(|module| segments.next() == Some(module))
+}
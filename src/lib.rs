@@ -1,11 +1,11 @@
 use addr2line::Loader;
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, ensure, Result};
 use byteorder::{LittleEndian, ReadBytesExt};
 use cargo_metadata::MetadataCommand;
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     env::var_os,
-    fs::{metadata, File, OpenOptions},
+    fs::{metadata, read_to_string, remove_file, File, OpenOptions},
     io::Write,
     path::{Path, PathBuf},
 };
@@ -31,15 +31,61 @@ use anchor_cli_config as config;
 #[cfg(feature = "__anchor_cli")]
 pub use anchor_cli_config::{BootstrapMode, ConfigOverride, ProgramArch};
 
+pub mod append;
+
+pub mod attach;
+
+pub mod build;
+
+mod constraints;
+
+pub mod coverage_config;
+
+pub mod deploy;
+
+pub mod doctor;
+
+mod errors;
+
+mod events;
+
+mod exclusions;
+use exclusions::excluded_lines;
+
+mod idl;
+
 mod insn;
 use insn::Insn;
 
+mod instructions;
+
+pub mod merge;
+
+pub mod report;
+use report::{build_report, InvocationReport, REPORT_FILENAME};
+
+mod source;
+
 mod start_address;
 use start_address::start_address;
 
+pub mod test_configs;
+
+pub mod test_names;
+use test_names::{lcov_test_name, read_test_intervals, test_name};
+
+pub mod trace_dir;
+
+pub mod trace_writer;
+
+pub mod tracefile;
+
 pub mod util;
 use util::{files_with_extension, StripCurrentDir};
 
+pub mod validator_log;
+use validator_log::label_traces;
+
 mod vaddr;
 use vaddr::Vaddr;
 
@@ -47,18 +93,35 @@ use vaddr::Vaddr;
 struct Entry<'a> {
     file: &'a str,
     line: u32,
+    column: u32,
 }
 
 struct Dwarf {
     path: PathBuf,
     start_address: u64,
-    #[allow(dead_code, reason = "`vaddr` points into `loader`")]
     loader: &'static Loader,
     vaddr_entry_map: BTreeMap<u64, Entry<'static>>,
 }
 
-enum Outcome {
-    Lcov(PathBuf),
+/// A program counters file for which an lcov file was written
+struct Trace<'a> {
+    pcs_path: PathBuf,
+    lcov_path: PathBuf,
+    dwarf: &'a Dwarf,
+    /// The name of the test that produced the program counters file, if known
+    test_name: Option<String>,
+    /// The program invocation in the validator log that produced the program counters file, if
+    /// known
+    invocation: Option<InvocationReport>,
+    /// The hits per line written to the lcov file
+    file_line_count_map: FileLineCountMap<'static>,
+    /// Shifted `vaddr`s, each of which has an entry in `dwarf`'s `vaddr_entry_map`, and with
+    /// consecutive `vaddr`s referring to the same file, line, and column deduplicated
+    vaddrs: Vaddrs,
+}
+
+enum Outcome<'a> {
+    Lcov(Box<Trace<'a>>),
     ClosestMatch(PathBuf),
 }
 
@@ -85,11 +148,40 @@ struct Mismatch {
 
 type FileLineCountMap<'a> = BTreeMap<&'a str, BTreeMap<u32, usize>>;
 
-pub fn run(sbf_trace_dir: impl AsRef<Path>, debug: bool) -> Result<()> {
-    let mut lcov_paths = Vec::new();
+type FileContentsMap<'a> = BTreeMap<&'a str, String>;
+
+type FileExcludedLinesMap<'a> = BTreeMap<&'a str, BTreeSet<u32>>;
+
+#[derive(Debug, Default)]
+pub struct Options {
+    /// Dump each debug file's `vaddr`-to-location map rather than processing program counters
+    /// files
+    pub debug: bool,
+    /// A validator log with which to label program counters files
+    pub validator_log: Option<PathBuf>,
+    /// The directory containing the debug files, or `None` for the workspace's
+    /// `target/coverage/deploy`, to which coverage builds write them
+    pub debug_dir: Option<PathBuf>,
+    /// Fail if there are no debug files, or if a program counters file matches none of them
+    pub require_match: bool,
+    /// The directory containing `Anchor.toml`; empty for the current directory
+    pub workspace_root: PathBuf,
+    /// The exit code of the tests that produced the program counters files, if they failed
+    pub test_exit_code: Option<i32>,
+}
+
+pub fn run(sbf_trace_dir: impl AsRef<Path>, options: &Options) -> Result<()> {
+    let mut traces = Vec::new();
     let mut closest_match_paths = Vec::new();
 
-    let debug_paths = debug_paths()?;
+    let target_directory = target_directory()?;
+
+    let debug_dir = options
+        .debug_dir
+        .clone()
+        .unwrap_or_else(|| options.workspace_root.join(build::COVERAGE_DEPLOY_DIR));
+
+    let debug_paths = files_with_extension(&debug_dir, "debug")?;
 
     let dwarfs = debug_paths
         .into_iter()
@@ -97,23 +189,37 @@ pub fn run(sbf_trace_dir: impl AsRef<Path>, debug: bool) -> Result<()> {
         .collect::<Result<Vec<_>>>()?;
 
     if dwarfs.is_empty() {
+        ensure!(
+            !options.require_match,
+            "found no debug files in: {}",
+            debug_dir.strip_current_dir().display()
+        );
         eprintln!("Found no debug files");
         return Ok(());
     }
 
-    if debug {
+    if options.debug {
         for dwarf in dwarfs {
             dump_vaddr_entry_map(dwarf.vaddr_entry_map);
         }
         return Ok(());
     }
 
+    let file_contents_map = build_file_contents_map(&dwarfs)?;
+
+    let file_excluded_lines_map = build_file_excluded_lines_map(&file_contents_map);
+
     let pcs_paths = files_with_extension(&sbf_trace_dir, "pcs")?;
 
+    let test_intervals = read_test_intervals(sbf_trace_dir.as_ref())?;
+
+    remove_lcov_files(&pcs_paths)?;
+
     for pcs_path in &pcs_paths {
-        match process_pcs_path(&dwarfs, pcs_path)? {
-            Outcome::Lcov(lcov_path) => {
-                lcov_paths.push(lcov_path.strip_current_dir().to_path_buf());
+        let test_name = test_name(&test_intervals, pcs_path)?;
+        match process_pcs_path(&dwarfs, &file_excluded_lines_map, pcs_path, test_name)? {
+            Outcome::Lcov(trace) => {
+                traces.push(*trace);
             }
             Outcome::ClosestMatch(closest_match_path) => {
                 closest_match_paths.push(closest_match_path.strip_current_dir().to_path_buf());
@@ -121,30 +227,99 @@ pub fn run(sbf_trace_dir: impl AsRef<Path>, debug: bool) -> Result<()> {
         }
     }
 
+    ensure!(
+        !options.require_match || closest_match_paths.is_empty(),
+        "{} of {} program counters files match none of the debug files in `{}`; were the programs \
+         rebuilt after the traces were recorded? Closest match files written: \
+         {closest_match_paths:#?}",
+        closest_match_paths.len(),
+        pcs_paths.len(),
+        debug_dir.strip_current_dir().display()
+    );
+
+    if let Some(validator_log) = &options.validator_log {
+        label_traces(
+            &mut traces,
+            validator_log,
+            &options.workspace_root.join("Anchor.toml"),
+        )?;
+    }
+
+    let lcov_paths = traces
+        .iter()
+        .map(|trace| trace.lcov_path.strip_current_dir().to_path_buf())
+        .collect::<Vec<_>>();
+
+    let mut report = build_report(
+        &target_directory.join("idl"),
+        &dwarfs,
+        &file_contents_map,
+        &file_excluded_lines_map,
+        &traces,
+    )?;
+    report.test_exit_code = options.test_exit_code;
+
+    let report_path = sbf_trace_dir.as_ref().join(REPORT_FILENAME);
+    report.write(&report_path)?;
+
     eprintln!(
         "
 Processed {} of {} program counter files
 
 Lcov files written: {lcov_paths:#?}
 
+Program executions attributed to tests: {} of {}
+
+Program executions labeled from validator log: {} of {}
+
+JSON report written: {}
+
 Closest match files written: {closest_match_paths:#?}
 
+Lines excluded by coverage markers: {:#?}
+
+Instructions never executed: {:#?}
+
+Constraints whose failure paths were never exercised: {:#?}
+
+Errors never triggered: {:#?}
+
+Events never emitted: {:#?}
+
+Emit sites whose events could not be determined: {:#?}
+
 If you are done generating lcov files, try running:
 
     genhtml --output-directory coverage {}/*.lcov && open coverage/index.html
 ",
         lcov_paths.len(),
         pcs_paths.len(),
+        traces
+            .iter()
+            .filter(|trace| trace.test_name.is_some())
+            .count(),
+        traces.len(),
+        traces
+            .iter()
+            .filter(|trace| trace.invocation.is_some())
+            .count(),
+        traces.len(),
+        report_path.strip_current_dir().display(),
+        excluded_line_counts(&dwarfs, &file_excluded_lines_map),
+        report.unexecuted_instructions(),
+        report.unfailed_constraints(),
+        report.untriggered_errors(),
+        report.unemitted_events(),
+        report.unresolved_emit_sites(),
         sbf_trace_dir.as_ref().strip_current_dir().display()
     );
 
     Ok(())
 }
 
-fn debug_paths() -> Result<Vec<PathBuf>> {
+fn target_directory() -> Result<PathBuf> {
     let metadata = MetadataCommand::new().no_deps().exec()?;
-    let target_directory = metadata.target_directory;
-    files_with_extension(target_directory.join("deploy"), "debug")
+    Ok(metadata.target_directory.into())
 }
 
 fn build_dwarf(debug_path: &Path) -> Result<Dwarf> {
@@ -170,13 +345,111 @@ fn build_dwarf(debug_path: &Path) -> Result<Dwarf> {
     })
 }
 
-fn process_pcs_path(dwarfs: &[Dwarf], pcs_path: &Path) -> Result<Outcome> {
+impl Dwarf {
+    /// Returns the name of the program, i.e., the debug file's stem
+    fn program_name(&self) -> String {
+        self.path
+            .file_stem()
+            .map(|file_stem| file_stem.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+
+    /// Returns the source files that `vaddr_entry_map` refers to
+    fn files(&self) -> BTreeSet<&'static str> {
+        self.vaddr_entry_map
+            .values()
+            .map(|Entry { file, .. }| *file)
+            .collect()
+    }
+
+    /// Returns the demangled names of the functions containing `vaddr`, innermost first
+    ///
+    /// If `vaddr` is within an inlined function, the functions into which it was inlined are
+    /// included.
+    fn function_names(&self, vaddr: u64) -> Result<Vec<String>> {
+        let mut frames = self
+            .loader
+            .find_frames(vaddr)
+            .map_err(|error| anyhow!("failed to find frames for address 0x{vaddr:x}: {error}"))?;
+        let mut function_names = Vec::new();
+        while let Some(frame) = frames.next()? {
+            if let Some(function) = frame.function {
+                function_names.push(function.demangle()?.into_owned());
+            }
+        }
+        Ok(function_names)
+    }
+}
+
+fn build_file_contents_map(dwarfs: &[Dwarf]) -> Result<FileContentsMap<'static>> {
+    let mut file_contents_map = FileContentsMap::new();
+    for dwarf in dwarfs {
+        for file in dwarf.files() {
+            if file_contents_map.contains_key(file) {
+                continue;
+            }
+            let contents = read_to_string(file)?;
+            file_contents_map.insert(file, contents);
+        }
+    }
+    Ok(file_contents_map)
+}
+
+fn build_file_excluded_lines_map<'a>(
+    file_contents_map: &FileContentsMap<'a>,
+) -> FileExcludedLinesMap<'a> {
+    file_contents_map
+        .iter()
+        .map(|(&file, contents)| (file, excluded_lines(contents)))
+        .collect()
+}
+
+/// Returns, for each file with excluded lines, the number of lines that would otherwise appear in
+/// the lcov files
+fn excluded_line_counts<'a>(
+    dwarfs: &[Dwarf],
+    file_excluded_lines_map: &FileExcludedLinesMap<'a>,
+) -> BTreeMap<&'a str, usize> {
+    let mut file_lines_map = BTreeMap::<&str, BTreeSet<u32>>::new();
+    for dwarf in dwarfs {
+        for Entry { file, line, .. } in dwarf.vaddr_entry_map.values() {
+            if is_excluded(file_excluded_lines_map, file, *line) {
+                file_lines_map.entry(file).or_default().insert(*line);
+            }
+        }
+    }
+    file_excluded_lines_map
+        .keys()
+        .filter_map(|&file| {
+            file_lines_map
+                .get(file)
+                .map(|excluded_lines| (file, excluded_lines.len()))
+        })
+        .collect()
+}
+
+fn is_excluded(file_excluded_lines_map: &FileExcludedLinesMap<'_>, file: &str, line: u32) -> bool {
+    file_excluded_lines_map
+        .get(file)
+        .is_some_and(|excluded_lines| excluded_lines.contains(&line))
+}
+
+fn process_pcs_path<'a>(
+    dwarfs: &'a [Dwarf],
+    file_excluded_lines_map: &FileExcludedLinesMap<'_>,
+    pcs_path: &Path,
+    test_name: Option<&str>,
+) -> Result<Outcome<'a>> {
     eprintln!();
     eprintln!(
         "Program counters file: {}",
         pcs_path.strip_current_dir().display()
     );
 
+    if let Some(test_name) = test_name {
+        eprintln!("Test: {test_name}");
+    }
+
     let mut vaddrs = read_vaddrs(pcs_path)?;
 
     eprintln!("Program counters read: {}", vaddrs.len());
@@ -196,22 +469,59 @@ fn process_pcs_path(dwarfs: &[Dwarf], pcs_path: &Path) -> Result<Outcome> {
         .first()
         .is_some_and(|&vaddr| vaddr == dwarf.start_address));
 
+    // smoelius: Reports that distinguish constraints on the same line need columns. So keep a copy
+    // of the `vaddr`s deduplicated by file, line, and column.
+    let mut trace_vaddrs = vaddrs.clone();
+    trace_vaddrs.dedup_by_key::<_, Option<&Entry>>(|vaddr| dwarf.vaddr_entry_map.get(vaddr));
+    let trace_vaddrs = keep_vaddrs_with_entries(dwarf, trace_vaddrs);
+
     // smoelius: If a sequence of program counters refer to the same file and line, treat them as
     // one hit to that file and line.
-    vaddrs.dedup_by_key::<_, Option<&Entry>>(|vaddr| dwarf.vaddr_entry_map.get(vaddr));
+    vaddrs.dedup_by_key::<_, Option<(&str, u32)>>(|vaddr| {
+        dwarf
+            .vaddr_entry_map
+            .get(vaddr)
+            .map(|entry| (entry.file, entry.line))
+    });
 
-    // smoelius: A `vaddr` could not have an entry because its file does not exist. Keep only those
-    // `vaddr`s that have entries.
-    let vaddrs = vaddrs
-        .into_iter()
-        .filter(|vaddr| dwarf.vaddr_entry_map.contains_key(vaddr))
-        .collect::<Vec<_>>();
+    let vaddrs = keep_vaddrs_with_entries(dwarf, vaddrs);
+
+    // smoelius: Hits to excluded lines are not counted in the totals.
+    let (included_vaddrs, excluded_vaddrs): (Vaddrs, Vaddrs) = vaddrs.iter().partition(|vaddr| {
+        let Entry { file, line, .. } = dwarf.vaddr_entry_map[vaddr];
+        !is_excluded(file_excluded_lines_map, file, line)
+    });
 
-    eprintln!("Line hits: {}", vaddrs.len());
+    eprintln!("Line hits: {}", included_vaddrs.len());
 
-    let file_line_count_map = build_file_line_count_map(&dwarf.vaddr_entry_map, vaddrs);
+    eprintln!("Excluded line hits: {}", excluded_vaddrs.len());
 
-    write_lcov_file(pcs_path, file_line_count_map).map(Outcome::Lcov)
+    let file_line_count_map = build_file_line_count_map(
+        &dwarf.vaddr_entry_map,
+        file_excluded_lines_map,
+        included_vaddrs,
+    );
+
+    let lcov_path = write_lcov_file(pcs_path, test_name, &file_line_count_map)?;
+
+    Ok(Outcome::Lcov(Box::new(Trace {
+        pcs_path: pcs_path.to_path_buf(),
+        lcov_path,
+        dwarf,
+        test_name: test_name.map(ToOwned::to_owned),
+        invocation: None,
+        file_line_count_map,
+        vaddrs: trace_vaddrs,
+    })))
+}
+
+// smoelius: A `vaddr` could not have an entry because its file does not exist. Keep only those
+// `vaddr`s that have entries.
+fn keep_vaddrs_with_entries(dwarf: &Dwarf, vaddrs: Vaddrs) -> Vaddrs {
+    vaddrs
+        .into_iter()
+        .filter(|vaddr| dwarf.vaddr_entry_map.contains_key(vaddr))
+        .collect()
 }
 
 static CARGO_HOME: std::sync::LazyLock<PathBuf> = std::sync::LazyLock::new(|| {
@@ -250,20 +560,22 @@ fn build_vaddr_entry_map<'a>(loader: &'a Loader, debug_path: &Path) -> Result<Va
         let Some(line) = location.line else {
             continue;
         };
-        // smoelius: Even though we ignore columns, fetch them should we ever want to act on them.
-        let Some(_column) = location.column else {
+        // smoelius: Columns are ignored by the lcov files, but are used to distinguish constraints
+        // that appear on the same line.
+        let Some(column) = location.column else {
             continue;
         };
         let entry = vaddr_entry_map.entry(vaddr).or_default();
         entry.file = file;
         entry.line = line;
+        entry.column = column;
     }
     Ok(vaddr_entry_map)
 }
 
 fn dump_vaddr_entry_map(vaddr_entry_map: BTreeMap<u64, Entry<'_>>) {
     let mut prev = String::new();
-    for (vaddr, Entry { file, line }) in vaddr_entry_map {
+    for (vaddr, Entry { file, line, .. }) in vaddr_entry_map {
         let curr = format!("{file}:{line}");
         if prev != curr {
             eprintln!("0x{vaddr:x}: {curr}");
@@ -394,10 +706,15 @@ fn write_closest_match(pcs_path: &Path, dwarf: &Dwarf, mismatch: Mismatch) -> Re
 
 fn build_file_line_count_map<'a>(
     vaddr_entry_map: &BTreeMap<u64, Entry<'a>>,
+    file_excluded_lines_map: &FileExcludedLinesMap<'_>,
     vaddrs: Vaddrs,
 ) -> FileLineCountMap<'a> {
     let mut file_line_count_map = FileLineCountMap::new();
-    for Entry { file, line } in vaddr_entry_map.values() {
+    for Entry { file, line, .. } in vaddr_entry_map.values() {
+        // smoelius: Excluded lines do not receive `DA` records.
+        if is_excluded(file_excluded_lines_map, file, *line) {
+            continue;
+        }
         let line_count_map = file_line_count_map.entry(file).or_default();
         line_count_map.insert(*line, 0);
     }
@@ -415,7 +732,44 @@ fn build_file_line_count_map<'a>(
     file_line_count_map
 }
 
-fn write_lcov_file(pcs_path: &Path, file_line_count_map: FileLineCountMap<'_>) -> Result<PathBuf> {
+/// Removes the lcov files previously written for `pcs_paths`, labeled or not
+///
+/// Otherwise, a labeled lcov file from an earlier run could sit next to the unlabeled one written
+/// by this run, and its hits would be counted twice.
+fn remove_lcov_files(pcs_paths: &[PathBuf]) -> Result<()> {
+    let mut stems_by_dir = BTreeMap::<&Path, BTreeSet<String>>::new();
+    for pcs_path in pcs_paths {
+        if let (Some(dir), Some(stem)) = (pcs_path.parent(), pcs_path.file_stem()) {
+            stems_by_dir
+                .entry(dir)
+                .or_default()
+                .insert(stem.to_string_lossy().into_owned());
+        }
+    }
+    for (dir, stems) in stems_by_dir {
+        for lcov_path in files_with_extension(dir, "lcov")? {
+            // smoelius: A labeled lcov file's name is the trace's stem followed by the labels,
+            // e.g., `1234.5VERv8NM.success.lcov`.
+            let file_name = lcov_path.file_name().unwrap_or_default().to_string_lossy();
+            let file_stem = file_name.strip_suffix(".lcov").unwrap_or_default();
+            if stems.iter().any(|stem| {
+                file_stem == stem
+                    || file_stem
+                        .strip_prefix(stem.as_str())
+                        .is_some_and(|labels| labels.starts_with('.'))
+            }) {
+                remove_file(&lcov_path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_lcov_file(
+    pcs_path: &Path,
+    test_name: Option<&str>,
+    file_line_count_map: &FileLineCountMap<'_>,
+) -> Result<PathBuf> {
     let lcov_path = Path::new(pcs_path).with_extension("lcov");
 
     let mut file = OpenOptions::new()
@@ -425,6 +779,9 @@ fn write_lcov_file(pcs_path: &Path, file_line_count_map: FileLineCountMap<'_>) -
         .open(&lcov_path)?;
 
     for (source_file, line_count_map) in file_line_count_map {
+        if let Some(test_name) = test_name {
+            writeln!(file, "TN:{}", lcov_test_name(test_name))?;
+        }
         // smoelius: Stripping `current_dir` from `source_file` has not effect on what's displayed.
         writeln!(file, "SF:{source_file}")?;
         for (line, count) in line_count_map {
@@ -0,0 +1,148 @@
+//! Merges lcov files and JSON reports, e.g., from several CI jobs, into a single report.
+
+use crate::{report::Report, tracefile::Tracefile};
+use anyhow::{anyhow, bail, Result};
+use std::{
+    ffi::OsStr,
+    fs::write,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+    Lcov,
+    Json,
+}
+
+impl Format {
+    /// Returns the format implied by `path`'s extension: JSON for `.json`, lcov otherwise
+    #[must_use]
+    pub fn from_path(path: &Path) -> Self {
+        if path.extension() == Some(OsStr::new("json")) {
+            Self::Json
+        } else {
+            Self::Lcov
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "lcov" => Ok(Self::Lcov),
+            "json" => Ok(Self::Json),
+            _ => bail!("unknown format `{s}`; expected `lcov` or `json`"),
+        }
+    }
+}
+
+/// A rule that replaces the prefix `from` of a source file path with `to`
+#[derive(Clone, Debug)]
+pub struct Remap {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+impl FromStr for Remap {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (from, to) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("remapping rule `{s}` is not of the form `FROM=TO`"))?;
+        Ok(Self {
+            from: PathBuf::from(from),
+            to: PathBuf::from(to),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct MergeOptions {
+    /// lcov files and JSON reports; files ending in `.json` are treated as JSON reports
+    pub inputs: Vec<PathBuf>,
+    /// Applied in order; the first rule whose `from` is a prefix of a path is used
+    pub remaps: Vec<Remap>,
+    pub output: PathBuf,
+    /// `None` to choose the format based on `output`'s extension
+    pub format: Option<Format>,
+}
+
+pub fn merge(options: &MergeOptions) -> Result<()> {
+    let remap = |path: &Path| remap_path(&options.remaps, path);
+
+    let mut tracefile = Tracefile::default();
+    let mut report = Report::default();
+
+    for input in &options.inputs {
+        if Format::from_path(input) == Format::Json {
+            report.merge(Report::read(input)?.remap(remap));
+        } else {
+            tracefile.merge(Tracefile::read(input)?.remap(remap));
+        }
+    }
+
+    let format = options
+        .format
+        .unwrap_or_else(|| Format::from_path(&options.output));
+
+    match format {
+        Format::Lcov => {
+            // smoelius: JSON reports contribute their line hits without test names.
+            for (file, line_hits) in std::mem::take(&mut report.files) {
+                let record = tracefile.records.entry((String::new(), file)).or_default();
+                for (line, hits) in line_hits {
+                    *record.lines.entry(line).or_default() += hits;
+                }
+            }
+            write(&options.output, tracefile.to_string())?;
+        }
+        Format::Json => {
+            let mut lcov_report = Report {
+                files: tracefile.line_hits(),
+                ..Report::default()
+            };
+            for ((test_name, file), record) in &tracefile.records {
+                if test_name.is_empty() {
+                    continue;
+                }
+                let line_tests = lcov_report.tests_by_line.entry(file.clone()).or_default();
+                for (&line, &hits) in &record.lines {
+                    if hits > 0 {
+                        line_tests
+                            .entry(line)
+                            .or_default()
+                            .insert(test_name.clone());
+                    }
+                }
+            }
+            report.merge(lcov_report);
+            report.write(&options.output)?;
+        }
+    }
+
+    eprintln!(
+        "Merged {} inputs into: {}",
+        options.inputs.len(),
+        options.output.display()
+    );
+
+    Ok(())
+}
+
+fn remap_path(remaps: &[Remap], path: &Path) -> PathBuf {
+    remaps
+        .iter()
+        .find_map(|Remap { from, to }| {
+            let suffix = path.strip_prefix(from).ok()?;
+            Some(if suffix.as_os_str().is_empty() {
+                to.clone()
+            } else {
+                to.join(suffix)
+            })
+        })
+        .unwrap_or_else(|| path.to_path_buf())
+}
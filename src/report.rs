@@ -0,0 +1,619 @@
+//! The JSON report written to the trace directory alongside the lcov files.
+
+use crate::{
+    constraints::accounts_reports,
+    errors::error_reports,
+    events::event_reports,
+    idl::read_idl,
+    instructions::{executed_handlers, instruction_reports},
+    is_excluded,
+    source::{handler_contexts, program_module},
+    Dwarf, FileContentsMap, FileExcludedLinesMap, Trace, CARGO_HOME,
+};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{read_to_string, write},
+    path::{Path, PathBuf},
+};
+
+pub const REPORT_FILENAME: &str = "coverage.json";
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Report {
+    pub traces: Vec<TraceReport>,
+    pub programs: Vec<ProgramReport>,
+    /// For each source file and line, the names of the tests that executed the line
+    ///
+    /// Only program executions attributed to tests contribute to this index.
+    pub tests_by_line: BTreeMap<PathBuf, BTreeMap<u32, BTreeSet<String>>>,
+    /// For each source file, the hits per line summed over all program executions
+    #[serde(default)]
+    pub files: BTreeMap<PathBuf, BTreeMap<u32, usize>>,
+    /// The exit code of `anchor test`, if the tests failed and coverage was computed anyway
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub test_exit_code: Option<i32>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TraceReport {
+    pub pcs_path: PathBuf,
+    pub lcov_path: PathBuf,
+    pub debug_path: PathBuf,
+    /// The name of the test that produced the program counters file, if known
+    pub test: Option<String>,
+    /// The program invocation in the validator log that produced the program counters file, if
+    /// known
+    pub invocation: Option<InvocationReport>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct InvocationReport {
+    pub program_id: String,
+    /// The signature of the transaction containing the invocation, if the log contains signatures
+    pub signature: Option<String>,
+    /// The index of the invocation's instruction within its transaction, if the invocation is a
+    /// top-level instruction and the log contains signatures
+    pub instruction_index: Option<usize>,
+    /// 1 for a top-level instruction, 2 for a CPI made by a top-level instruction, etc.
+    pub stack_height: usize,
+    pub compute_units: Option<u64>,
+    /// `None` if the log ends before the invocation does
+    pub success: Option<bool>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ProgramReport {
+    pub name: String,
+    pub debug_path: PathBuf,
+    /// Empty if the program has no IDL
+    pub instructions: Vec<InstructionReport>,
+    pub accounts: Vec<AccountsReport>,
+    /// Variants of the program's `#[error_code]` enums
+    pub errors: Vec<ErrorReport>,
+    pub error_sites: Vec<ErrorSiteReport>,
+    pub events: Vec<EventReport>,
+    /// Invocations of `emit!` or `emit_cpi!` whose event could not be determined, e.g., because
+    /// the argument is a variable
+    #[serde(default)]
+    pub unresolved_emit_sites: Vec<EmitSiteReport>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct InstructionReport {
+    pub name: String,
+    /// Path of the handler function, relative to the program's crate
+    pub handler: String,
+    /// Number of program executions in which the handler was executed
+    pub executions: usize,
+}
+
+/// A struct deriving `Accounts`
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AccountsReport {
+    pub name: String,
+    pub file: PathBuf,
+    pub line: u32,
+    pub fields: Vec<FieldReport>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FieldReport {
+    pub name: String,
+    pub line: u32,
+    /// Number of program executions in which any of the field's checks were executed
+    pub executions: usize,
+    pub constraints: Vec<ConstraintReport>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ConstraintReport {
+    /// The constraint as written, e.g., `has_one = authority`
+    pub constraint: String,
+    pub line: u32,
+    pub column: u32,
+    /// Number of program executions in which the constraint was checked
+    pub executions: usize,
+    /// Number of program executions in which the constraint's check failed, or `None` if no
+    /// handler takes the constraint's struct
+    ///
+    /// Failures are inferred heuristically; see the README's "Account constraint coverage".
+    pub failures: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ErrorReport {
+    pub name: String,
+    /// The error's code according to the IDL, if the program has one
+    pub code: Option<u32>,
+    pub file: PathBuf,
+    pub line: u32,
+    /// Number of program executions in which the error was triggered
+    pub executions: usize,
+}
+
+/// An invocation of `require!`, `err!`, or a similar macro
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ErrorSiteReport {
+    pub macro_name: String,
+    /// The error argument as written, or `None` if the macro uses one of Anchor's built-in errors
+    pub error: Option<String>,
+    pub file: PathBuf,
+    pub line: u32,
+    /// Number of program executions in which the invocation was executed
+    pub executions: usize,
+    /// Number of program executions in which the invocation's error path was executed, or `None`
+    /// if this cannot be determined
+    pub error_path_executions: Option<usize>,
+}
+
+/// An `#[event]` struct, or an event in the program's IDL
+#[derive(Debug, Deserialize, Serialize)]
+pub struct EventReport {
+    pub name: String,
+    /// The file containing the event's definition, or `None` if the event appears only in the IDL
+    pub file: Option<PathBuf>,
+    pub line: Option<u32>,
+    /// Number of program executions in which the event was emitted
+    pub executions: usize,
+    /// Invocations of `emit!` or `emit_cpi!` that emit the event
+    pub sites: Vec<EmitSiteReport>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct EmitSiteReport {
+    pub macro_name: String,
+    pub file: PathBuf,
+    pub line: u32,
+    /// Number of program executions in which the invocation was executed
+    pub executions: usize,
+}
+
+impl Report {
+    pub fn read(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = read_to_string(path)?;
+        serde_json::from_str(&contents)
+            .with_context(|| format!("failed to parse JSON report: {}", path.display()))
+    }
+
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        write(path, json)?;
+        Ok(())
+    }
+
+    /// Returns the test exit code recorded in the report in `dir`, or `None` if there is no report
+    ///
+    /// The exit code is not recoverable from the traces, so `anchor-coverage report` uses this to
+    /// carry it over to the report it regenerates.
+    pub fn recorded_test_exit_code(dir: &Path) -> Result<Option<i32>> {
+        let path = dir.join(REPORT_FILENAME);
+        if !path.try_exists()? {
+            return Ok(None);
+        }
+        Self::read(path).map(|report| report.test_exit_code)
+    }
+
+    /// Adds `other`'s counts to `self`'s
+    ///
+    /// Programs are matched by name, and their instructions, accounts, errors, etc. are matched by
+    /// name and location.
+    pub fn merge(&mut self, other: Self) {
+        self.traces.extend(other.traces);
+        merge_by_key(
+            &mut self.programs,
+            other.programs,
+            |program| program.name.clone(),
+            ProgramReport::merge,
+        );
+        for (file, line_tests) in other.tests_by_line {
+            add_line_tests(&mut self.tests_by_line, file, line_tests);
+        }
+        for (file, line_hits) in other.files {
+            add_line_hits(&mut self.files, file, line_hits);
+        }
+        // smoelius: A nonzero exit code from any of the merged reports wins.
+        self.test_exit_code = self
+            .test_exit_code
+            .filter(|&code| code != 0)
+            .or(other.test_exit_code);
+    }
+
+    /// Applies `remap` to each source file path
+    #[must_use]
+    pub fn remap(mut self, remap: impl Fn(&Path) -> PathBuf) -> Self {
+        let remap_in_place = |path: &mut PathBuf| *path = remap(path);
+        for program in &mut self.programs {
+            for accounts in &mut program.accounts {
+                remap_in_place(&mut accounts.file);
+            }
+            for error in &mut program.errors {
+                remap_in_place(&mut error.file);
+            }
+            for error_site in &mut program.error_sites {
+                remap_in_place(&mut error_site.file);
+            }
+            for event in &mut program.events {
+                if let Some(file) = &mut event.file {
+                    remap_in_place(file);
+                }
+                for site in &mut event.sites {
+                    remap_in_place(&mut site.file);
+                }
+            }
+            for site in &mut program.unresolved_emit_sites {
+                remap_in_place(&mut site.file);
+            }
+        }
+        // smoelius: Entries for files that become the same are merged.
+        for (file, line_tests) in std::mem::take(&mut self.tests_by_line) {
+            add_line_tests(&mut self.tests_by_line, remap(&file), line_tests);
+        }
+        for (file, line_hits) in std::mem::take(&mut self.files) {
+            add_line_hits(&mut self.files, remap(&file), line_hits);
+        }
+        self
+    }
+
+    /// Returns the `program::instruction` names of instructions that were never executed
+    #[must_use]
+    pub fn unexecuted_instructions(&self) -> Vec<String> {
+        self.programs
+            .iter()
+            .flat_map(|program| {
+                program
+                    .instructions
+                    .iter()
+                    .filter(|instruction| instruction.executions == 0)
+                    .map(|instruction| format!("{}::{}", program.name, instruction.name))
+            })
+            .collect()
+    }
+
+    /// Returns the `program::Struct::field: constraint` names of constraints whose failure paths
+    /// were never exercised
+    #[must_use]
+    pub fn unfailed_constraints(&self) -> Vec<String> {
+        let mut unfailed_constraints = Vec::new();
+        for program in &self.programs {
+            for accounts in &program.accounts {
+                for field in &accounts.fields {
+                    for constraint in &field.constraints {
+                        if constraint.failures == Some(0) {
+                            unfailed_constraints.push(format!(
+                                "{}::{}::{}: {}",
+                                program.name, accounts.name, field.name, constraint.constraint
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+        unfailed_constraints
+    }
+
+    /// Returns the `program::Error` names of errors that were never triggered
+    #[must_use]
+    pub fn untriggered_errors(&self) -> Vec<String> {
+        self.programs
+            .iter()
+            .flat_map(|program| {
+                program
+                    .errors
+                    .iter()
+                    .filter(|error| error.executions == 0)
+                    .map(|error| format!("{}::{}", program.name, error.name))
+            })
+            .collect()
+    }
+
+    /// Returns the `program: file:line` locations of `emit!` and `emit_cpi!` invocations whose
+    /// event could not be determined
+    #[must_use]
+    pub fn unresolved_emit_sites(&self) -> Vec<String> {
+        self.programs
+            .iter()
+            .flat_map(|program| {
+                program
+                    .unresolved_emit_sites
+                    .iter()
+                    .map(|site| format!("{}: {}:{}", program.name, site.file.display(), site.line))
+            })
+            .collect()
+    }
+
+    /// Returns the `program::Event` names of events that were never emitted
+    #[must_use]
+    pub fn unemitted_events(&self) -> Vec<String> {
+        self.programs
+            .iter()
+            .flat_map(|program| {
+                program
+                    .events
+                    .iter()
+                    .filter(|event| event.executions == 0)
+                    .map(|event| format!("{}::{}", program.name, event.name))
+            })
+            .collect()
+    }
+}
+
+pub(crate) fn build_report(
+    idl_dir: &Path,
+    dwarfs: &[Dwarf],
+    file_contents_map: &FileContentsMap<'_>,
+    file_excluded_lines_map: &FileExcludedLinesMap<'_>,
+    traces: &[Trace<'_>],
+) -> Result<Report> {
+    let trace_reports = traces
+        .iter()
+        .map(|trace| TraceReport {
+            pcs_path: trace.pcs_path.clone(),
+            lcov_path: trace.lcov_path.clone(),
+            debug_path: trace.dwarf.path.clone(),
+            test: trace.test_name.clone(),
+            invocation: trace.invocation.clone(),
+        })
+        .collect();
+
+    let program_reports = dwarfs
+        .iter()
+        .map(|dwarf| build_program_report(idl_dir, dwarf, file_contents_map, traces))
+        .collect::<Result<_>>()?;
+
+    Ok(Report {
+        traces: trace_reports,
+        programs: program_reports,
+        tests_by_line: build_tests_by_line(file_excluded_lines_map, traces),
+        files: build_files(traces),
+        test_exit_code: None,
+    })
+}
+
+fn build_files(traces: &[Trace<'_>]) -> BTreeMap<PathBuf, BTreeMap<u32, usize>> {
+    let mut files = BTreeMap::new();
+    for trace in traces {
+        for (file, line_count_map) in &trace.file_line_count_map {
+            add_line_hits(&mut files, PathBuf::from(file), line_count_map.clone());
+        }
+    }
+    files
+}
+
+fn build_tests_by_line(
+    file_excluded_lines_map: &FileExcludedLinesMap<'_>,
+    traces: &[Trace<'_>],
+) -> BTreeMap<PathBuf, BTreeMap<u32, BTreeSet<String>>> {
+    let mut tests_by_line = BTreeMap::<PathBuf, BTreeMap<u32, BTreeSet<String>>>::new();
+    for trace in traces {
+        let Some(test_name) = &trace.test_name else {
+            continue;
+        };
+        for vaddr in &trace.vaddrs {
+            let entry = &trace.dwarf.vaddr_entry_map[vaddr];
+            if is_excluded(file_excluded_lines_map, entry.file, entry.line) {
+                continue;
+            }
+            tests_by_line
+                .entry(PathBuf::from(entry.file))
+                .or_default()
+                .entry(entry.line)
+                .or_default()
+                .insert(test_name.clone());
+        }
+    }
+    tests_by_line
+}
+
+fn build_program_report(
+    idl_dir: &Path,
+    dwarf: &Dwarf,
+    file_contents_map: &FileContentsMap<'_>,
+    traces: &[Trace<'_>],
+) -> Result<ProgramReport> {
+    let name = dwarf.program_name();
+
+    let traces = traces
+        .iter()
+        .filter(|trace| std::ptr::eq(trace.dwarf, dwarf))
+        .collect::<Vec<_>>();
+
+    let sources = program_sources(dwarf, file_contents_map);
+
+    let module = sources
+        .iter()
+        .find_map(|(_, contents)| program_module(contents));
+
+    let handler_contexts = sources
+        .iter()
+        .flat_map(|(_, contents)| handler_contexts(contents))
+        .collect::<Vec<_>>();
+
+    let idl = read_idl(idl_dir, &name)?;
+
+    let handlers = idl
+        .iter()
+        .flat_map(|idl| idl.instructions.iter().map(|instruction| &instruction.name))
+        .chain(handler_contexts.iter().map(|(handler, _)| handler))
+        .cloned()
+        .collect::<BTreeSet<_>>();
+
+    let executed_handlers = executed_handlers(dwarf, module.as_deref(), &handlers, &traces)?;
+
+    let instructions = idl
+        .as_ref()
+        .map(|idl| instruction_reports(idl, module.as_deref(), &executed_handlers))
+        .unwrap_or_default();
+
+    let accounts = accounts_reports(
+        dwarf,
+        &sources,
+        &handler_contexts,
+        &traces,
+        &executed_handlers,
+    );
+
+    let (errors, error_sites) = error_reports(dwarf, &sources, idl.as_ref(), &traces);
+
+    let (events, unresolved_emit_sites) = event_reports(dwarf, &sources, idl.as_ref(), &traces);
+
+    Ok(ProgramReport {
+        name,
+        debug_path: dwarf.path.clone(),
+        instructions,
+        accounts,
+        errors,
+        error_sites,
+        events,
+        unresolved_emit_sites,
+    })
+}
+
+impl ProgramReport {
+    fn merge(&mut self, other: Self) {
+        merge_by_key(
+            &mut self.instructions,
+            other.instructions,
+            |instruction| instruction.name.clone(),
+            |instruction, other| instruction.executions += other.executions,
+        );
+        merge_by_key(
+            &mut self.accounts,
+            other.accounts,
+            |accounts| (accounts.name.clone(), accounts.file.clone(), accounts.line),
+            |accounts, other| {
+                merge_by_key(
+                    &mut accounts.fields,
+                    other.fields,
+                    |field| field.name.clone(),
+                    |field, other| {
+                        field.executions += other.executions;
+                        merge_by_key(
+                            &mut field.constraints,
+                            other.constraints,
+                            |constraint| (constraint.line, constraint.column),
+                            |constraint, other| {
+                                constraint.executions += other.executions;
+                                constraint.failures =
+                                    add_options(constraint.failures, other.failures);
+                            },
+                        );
+                    },
+                );
+            },
+        );
+        merge_by_key(
+            &mut self.errors,
+            other.errors,
+            |error| (error.name.clone(), error.file.clone(), error.line),
+            |error, other| {
+                error.code = error.code.or(other.code);
+                error.executions += other.executions;
+            },
+        );
+        merge_by_key(
+            &mut self.error_sites,
+            other.error_sites,
+            |error_site| {
+                (
+                    error_site.file.clone(),
+                    error_site.line,
+                    error_site.macro_name.clone(),
+                )
+            },
+            |error_site, other| {
+                error_site.executions += other.executions;
+                error_site.error_path_executions = add_options(
+                    error_site.error_path_executions,
+                    other.error_path_executions,
+                );
+            },
+        );
+        merge_by_key(
+            &mut self.events,
+            other.events,
+            |event| event.name.clone(),
+            |event, other| {
+                event.file = event.file.take().or(other.file);
+                event.line = event.line.or(other.line);
+                event.executions += other.executions;
+                merge_by_key(
+                    &mut event.sites,
+                    other.sites,
+                    |site| (site.file.clone(), site.line, site.macro_name.clone()),
+                    |site, other| site.executions += other.executions,
+                );
+            },
+        );
+        merge_by_key(
+            &mut self.unresolved_emit_sites,
+            other.unresolved_emit_sites,
+            |site| (site.file.clone(), site.line, site.macro_name.clone()),
+            |site, other| site.executions += other.executions,
+        );
+    }
+}
+
+/// Merges each element of `from` into the element of `into` with the same key, or appends it to
+/// `into` if there is none
+fn merge_by_key<T, K: PartialEq>(
+    into: &mut Vec<T>,
+    from: Vec<T>,
+    key: impl Fn(&T) -> K,
+    merge: impl Fn(&mut T, T),
+) {
+    for other in from {
+        let other_key = key(&other);
+        if let Some(existing) = into.iter_mut().find(|existing| key(existing) == other_key) {
+            merge(existing, other);
+        } else {
+            into.push(other);
+        }
+    }
+}
+
+fn add_line_tests(
+    tests_by_line: &mut BTreeMap<PathBuf, BTreeMap<u32, BTreeSet<String>>>,
+    file: PathBuf,
+    line_tests: BTreeMap<u32, BTreeSet<String>>,
+) {
+    let existing = tests_by_line.entry(file).or_default();
+    for (line, tests) in line_tests {
+        existing.entry(line).or_default().extend(tests);
+    }
+}
+
+fn add_line_hits(
+    files: &mut BTreeMap<PathBuf, BTreeMap<u32, usize>>,
+    file: PathBuf,
+    line_hits: BTreeMap<u32, usize>,
+) {
+    let existing = files.entry(file).or_default();
+    for (line, hits) in line_hits {
+        *existing.entry(line).or_default() += hits;
+    }
+}
+
+fn add_options(left: Option<usize>, right: Option<usize>) -> Option<usize> {
+    match (left, right) {
+        (Some(left), Some(right)) => Some(left + right),
+        (left, right) => left.or(right),
+    }
+}
+
+/// Returns the program's source files and their contents, excluding files under `CARGO_HOME`
+fn program_sources<'a>(
+    dwarf: &Dwarf,
+    file_contents_map: &'a FileContentsMap<'_>,
+) -> Vec<(&'a str, &'a str)> {
+    let files = dwarf.files();
+    let cargo_home = CARGO_HOME.to_string_lossy();
+    file_contents_map
+        .iter()
+        .filter(|(file, _)| files.contains(*file) && !file.starts_with(cargo_home.as_ref()))
+        .map(|(file, contents)| (*file, contents.as_str()))
+        .collect()
+}
@@ -0,0 +1,694 @@
+//! Helpers for locating Anchor constructs in program source files.
+//!
+//! The helpers operate on lines of text rather than on a parsed syntax tree. They are meant to be
+//! applied to `rustfmt`-formatted Anchor programs and are not expected to handle arbitrary Rust.
+
+use std::ops::{Range, RangeInclusive};
+
+/// Returns the name of the module annotated with `#[program]`, if any
+pub(crate) fn program_module(contents: &str) -> Option<String> {
+    let code = strip_comments(contents);
+    let start = code.find("#[program]")?;
+    let mut words = words(&code[start + "#[program]".len()..]);
+    words.find(|&word| word == "mod")?;
+    words.next().map(ToOwned::to_owned)
+}
+
+/// A struct deriving `Accounts`
+#[derive(Debug)]
+pub(crate) struct AccountsStruct {
+    pub name: String,
+    pub line: u32,
+    pub fields: Vec<AccountsField>,
+}
+
+#[derive(Debug)]
+pub(crate) struct AccountsField {
+    pub name: String,
+    /// Line of the field's name
+    pub line: u32,
+    /// Lines of the field's attributes and declaration
+    pub lines: RangeInclusive<u32>,
+    pub constraints: Vec<Constraint>,
+}
+
+/// One comma-separated element of an `#[account(...)]` attribute, e.g., `has_one = authority`
+#[derive(Debug)]
+pub(crate) struct Constraint {
+    pub text: String,
+    pub span: Span,
+}
+
+/// A region of a source file, from `start` up to but not including `end`
+///
+/// Positions are (line, column) pairs. Both are one-based, as in DWARF.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct Span {
+    pub start: (u32, u32),
+    pub end: (u32, u32),
+}
+
+impl Span {
+    /// Returns true if the span contains `line` and `column`
+    ///
+    /// A column of zero means the column is unknown, in which case only the line is considered.
+    pub(crate) fn contains(&self, line: u32, column: u32) -> bool {
+        if column == 0 {
+            return (self.start.0..=self.end.0).contains(&line);
+        }
+        (self.start..self.end).contains(&(line, column))
+    }
+}
+
+/// Returns the structs in `contents` that derive `Accounts`
+pub(crate) fn accounts_structs(contents: &str) -> Vec<AccountsStruct> {
+    let code = Code::new(contents);
+    let mut accounts_structs = Vec::new();
+    let mut from = 0;
+    while let Some(derive) = code.find(from, "#[derive(") {
+        let Some(close) = code.matching(derive + 1) else {
+            break;
+        };
+        from = close + 1;
+        if !words(&code.text(derive..close)).any(|word| word == "Accounts") {
+            continue;
+        }
+        let Some(keyword) = code.find_word(from, "struct") else {
+            break;
+        };
+        let Some((name_index, name)) = code.ident(keyword + "struct".len()) else {
+            break;
+        };
+        let Some(open) = code.find(name_index, "{") else {
+            break;
+        };
+        let Some(close) = code.matching(open) else {
+            break;
+        };
+        accounts_structs.push(AccountsStruct {
+            name,
+            line: code.chars[name_index].line,
+            fields: code.accounts_fields(open + 1, close),
+        });
+        from = close + 1;
+    }
+    accounts_structs
+}
+
+/// Returns (handler, `Accounts` struct) pairs for the functions in the `#[program]` module
+///
+/// For example, `pub fn initialize(ctx: Context<Initialize>)` produces `("initialize",
+/// "Initialize")`.
+pub(crate) fn handler_contexts(contents: &str) -> Vec<(String, String)> {
+    let code = Code::new(contents);
+    let Some(program) = code.find(0, "#[program]") else {
+        return Vec::new();
+    };
+    let Some(open) = code.find(program, "{") else {
+        return Vec::new();
+    };
+    let Some(close) = code.matching(open) else {
+        return Vec::new();
+    };
+    let mut handler_contexts = Vec::new();
+    let mut from = open;
+    while let Some(keyword) = code.find_word(from, "fn").filter(|&index| index < close) {
+        from = keyword + "fn".len();
+        let Some((name_index, name)) = code.ident(from) else {
+            break;
+        };
+        let Some(params_open) = code.find(name_index, "(") else {
+            break;
+        };
+        let Some(params_close) = code.matching(params_open) else {
+            break;
+        };
+        from = params_close;
+        let Some(context) = code
+            .find_word(params_open, "Context")
+            .filter(|&index| index < params_close)
+        else {
+            continue;
+        };
+        let Some(generics_open) = code.find(context, "<") else {
+            continue;
+        };
+        let Some(generics_close) = code.matching(generics_open) else {
+            continue;
+        };
+        // smoelius: The `Accounts` struct is the last generic argument, e.g., `Context<'_, '_, '_,
+        // 'info, Initialize<'info>>`.
+        let Some(last) = code
+            .split_top_level(generics_open + 1, generics_close, true)
+            .pop()
+        else {
+            continue;
+        };
+        if let Some((_, accounts)) = code.ident(last.start) {
+            handler_contexts.push((name, accounts));
+        }
+    }
+    handler_contexts
+}
+
+/// An enum annotated with `#[error_code]`
+#[derive(Debug)]
+pub(crate) struct ErrorEnum {
+    pub name: String,
+    pub lines: RangeInclusive<u32>,
+    pub variants: Vec<ErrorVariant>,
+}
+
+#[derive(Debug)]
+pub(crate) struct ErrorVariant {
+    pub name: String,
+    pub line: u32,
+    /// Lines of the variant's attributes (e.g., `#[msg(...)]`) and name
+    pub lines: RangeInclusive<u32>,
+}
+
+/// An invocation of a macro that returns or constructs an error, e.g., `require!(...)`
+#[derive(Debug)]
+pub(crate) struct ErrorSite {
+    pub macro_name: String,
+    /// The error argument as written, e.g., `VaultError::Unauthorized`, or `None` if the macro
+    /// uses one of Anchor's built-in errors
+    pub error: Option<String>,
+    pub lines: RangeInclusive<u32>,
+}
+
+/// Macros that return or construct errors, and the number of arguments each takes when an error
+/// is given explicitly
+const ERROR_MACROS: &[(&str, usize)] = &[
+    ("err", 1),
+    ("error", 1),
+    ("require", 2),
+    ("require_eq", 3),
+    ("require_gt", 3),
+    ("require_gte", 3),
+    ("require_keys_eq", 3),
+    ("require_keys_neq", 3),
+    ("require_neq", 3),
+];
+
+/// Returns the enums in `contents` annotated with `#[error_code]`
+pub(crate) fn error_enums(contents: &str) -> Vec<ErrorEnum> {
+    let code = Code::new(contents);
+    let mut error_enums = Vec::new();
+    let mut from = 0;
+    while let Some(attribute) = code.find(from, "#[error_code") {
+        let Some(keyword) = code.find_word(attribute, "enum") else {
+            break;
+        };
+        let Some((name_index, name)) = code.ident(keyword + "enum".len()) else {
+            break;
+        };
+        let Some(open) = code.find(name_index, "{") else {
+            break;
+        };
+        let Some(close) = code.matching(open) else {
+            break;
+        };
+        error_enums.push(ErrorEnum {
+            name,
+            lines: code.lines(&(attribute..close + 1)),
+            variants: code.error_variants(open + 1, close),
+        });
+        from = close + 1;
+    }
+    error_enums
+}
+
+/// Returns the invocations in `contents` of macros that return or construct errors
+pub(crate) fn error_sites(contents: &str) -> Vec<ErrorSite> {
+    let code = Code::new(contents);
+    let mut error_sites = Vec::new();
+    for &(macro_name, explicit_arity) in ERROR_MACROS {
+        for (index, open, close) in code.macro_invocations(macro_name) {
+            let args = code.split_top_level(open + 1, close, false);
+            let error = if args.len() == explicit_arity {
+                args.last().map(|range| code.text(range.clone()))
+            } else {
+                None
+            };
+            error_sites.push(ErrorSite {
+                macro_name: macro_name.to_owned(),
+                error,
+                lines: code.lines(&(index..close + 1)),
+            });
+        }
+    }
+    error_sites.sort_by_key(|error_site| *error_site.lines.start());
+    error_sites
+}
+
+/// A struct annotated with `#[event]`
+#[derive(Debug)]
+pub(crate) struct EventStruct {
+    pub name: String,
+    pub line: u32,
+}
+
+/// An invocation of `emit!` or `emit_cpi!`
+#[derive(Debug)]
+pub(crate) struct EmitSite {
+    pub macro_name: String,
+    /// The name of the emitted event's type, e.g., `Deposited`, or `None` if it cannot be
+    /// determined (e.g., because the event is a variable)
+    pub event: Option<String>,
+    pub lines: RangeInclusive<u32>,
+}
+
+const EMIT_MACROS: &[&str] = &["emit", "emit_cpi"];
+
+/// Returns the structs in `contents` annotated with `#[event]`
+pub(crate) fn event_structs(contents: &str) -> Vec<EventStruct> {
+    let code = Code::new(contents);
+    let mut event_structs = Vec::new();
+    let mut from = 0;
+    while let Some(attribute) = code.find(from, "#[event]") {
+        from = attribute + "#[event]".len();
+        let Some(keyword) = code.find_word(from, "struct") else {
+            break;
+        };
+        let Some((name_index, name)) = code.ident(keyword + "struct".len()) else {
+            break;
+        };
+        event_structs.push(EventStruct {
+            name,
+            line: code.chars[name_index].line,
+        });
+        from = name_index;
+    }
+    event_structs
+}
+
+/// Returns the invocations in `contents` of `emit!` and `emit_cpi!`
+pub(crate) fn emit_sites(contents: &str) -> Vec<EmitSite> {
+    let code = Code::new(contents);
+    let mut emit_sites = Vec::new();
+    for &macro_name in EMIT_MACROS {
+        for (index, open, close) in code.macro_invocations(macro_name) {
+            // smoelius: Only a struct expression names the event's type. An argument not followed
+            // by `{` or `(`, e.g., `emit!(event)`, is a variable, whose type is unknown.
+            let event = code.trim(open + 1..close).and_then(|arg| {
+                let end =
+                    (arg.start..arg.end).find(|&index| matches!(code.chars[index].c, '{' | '('))?;
+                // smoelius: Strip generic arguments, e.g., the `::<T>` in `Deposited::<T> { .. }`.
+                let path = code.text(arg.start..end);
+                let path = path.split('<').next()?.trim_end().trim_end_matches("::");
+                let name = path.rsplit("::").next()?.trim();
+                (!name.is_empty() && name.chars().all(is_ident_char)).then(|| name.to_owned())
+            });
+            emit_sites.push(EmitSite {
+                macro_name: macro_name.to_owned(),
+                event,
+                lines: code.lines(&(index..close + 1)),
+            });
+        }
+    }
+    emit_sites.sort_by_key(|emit_site| *emit_site.lines.start());
+    emit_sites
+}
+
+/// A character of code and its position
+#[derive(Clone, Copy, Debug)]
+struct Char {
+    c: char,
+    line: u32,
+    column: u32,
+    /// True if the character is within a string literal, including its quotes
+    in_string: bool,
+}
+
+/// The code of a source file, i.e., its contents with line comments removed
+struct Code {
+    chars: Vec<Char>,
+}
+
+impl Code {
+    fn new(contents: &str) -> Self {
+        let mut chars = Vec::new();
+        let mut in_string = false;
+        let mut escaped = false;
+        for (line_index, line) in contents.lines().enumerate() {
+            let line_number = u32::try_from(line_index + 1).unwrap_or(u32::MAX);
+            let code = if in_string {
+                line
+            } else {
+                split_comment(line).0
+            };
+            for (byte_index, c) in code.char_indices() {
+                // smoelius: Treat `'"'` as a character literal, not the start of a string.
+                let is_quote = c == '"'
+                    && !escaped
+                    && !(code[..byte_index].ends_with('\'')
+                        && code[byte_index + 1..].starts_with('\''));
+                let closes_string = is_quote && in_string;
+                if is_quote {
+                    in_string = true;
+                }
+                chars.push(Char {
+                    c,
+                    line: line_number,
+                    column: u32::try_from(byte_index + 1).unwrap_or(u32::MAX),
+                    in_string,
+                });
+                if closes_string {
+                    in_string = false;
+                }
+                escaped = in_string && c == '\\' && !escaped;
+            }
+            chars.push(Char {
+                c: '\n',
+                line: line_number,
+                column: u32::try_from(line.len() + 1).unwrap_or(u32::MAX),
+                in_string,
+            });
+        }
+        Self { chars }
+    }
+
+    fn text(&self, range: Range<usize>) -> String {
+        self.chars[range].iter().map(|c| c.c).collect()
+    }
+
+    /// Returns the index of the first occurrence of `pattern` at or after `from`, outside of string
+    /// literals
+    fn find(&self, from: usize, pattern: &str) -> Option<usize> {
+        let pattern = pattern.chars().collect::<Vec<_>>();
+        (from..self.chars.len()).find(|&index| {
+            self.chars[index..]
+                .iter()
+                .map(|c| (c.c, c.in_string))
+                .take(pattern.len())
+                .eq(pattern.iter().map(|&c| (c, false)))
+        })
+    }
+
+    /// Like [`Code::find`], but requires that `word` not be part of a larger identifier
+    fn find_word(&self, mut from: usize, word: &str) -> Option<usize> {
+        loop {
+            let index = self.find(from, word)?;
+            let before = index
+                .checked_sub(1)
+                .is_some_and(|before| is_ident_char(self.chars[before].c));
+            let after = self
+                .chars
+                .get(index + word.len())
+                .is_some_and(|c| is_ident_char(c.c));
+            if !before && !after {
+                return Some(index);
+            }
+            from = index + 1;
+        }
+    }
+
+    /// Returns the index and text of the first identifier at or after `from`
+    fn ident(&self, from: usize) -> Option<(usize, String)> {
+        let start = (from..self.chars.len()).find(|&index| {
+            let c = self.chars[index].c;
+            !c.is_whitespace() && c != '\''
+        })?;
+        let end = (start..self.chars.len())
+            .find(|&index| !is_ident_char(self.chars[index].c))
+            .unwrap_or(self.chars.len());
+        if start == end {
+            return None;
+        }
+        Some((start, self.text(start..end)))
+    }
+
+    /// Returns the invocations of the macro `macro_name`, as triples of the indices of the macro's
+    /// name and of its opening and closing delimiters
+    fn macro_invocations(&self, macro_name: &str) -> Vec<(usize, usize, usize)> {
+        let mut invocations = Vec::new();
+        let mut from = 0;
+        while let Some(index) = self.find_word(from, macro_name) {
+            from = index + macro_name.len();
+            let Some(bang) = self.trim(from..self.chars.len()).map(|range| range.start) else {
+                break;
+            };
+            if self.chars[bang].c != '!' {
+                continue;
+            }
+            let Some(open) = self
+                .trim(bang + 1..self.chars.len())
+                .map(|range| range.start)
+            else {
+                break;
+            };
+            let Some(close) = self.matching(open) else {
+                continue;
+            };
+            invocations.push((index, open, close));
+            from = close;
+        }
+        invocations
+    }
+
+    /// Returns the index of the delimiter that closes the one at `open`
+    fn matching(&self, open: usize) -> Option<usize> {
+        let open_c = self.chars[open].c;
+        let close_c = match open_c {
+            '(' => ')',
+            '[' => ']',
+            '{' => '}',
+            '<' => '>',
+            _ => return None,
+        };
+        let mut depth = 0usize;
+        for (index, c) in self.chars.iter().enumerate().skip(open) {
+            if c.in_string {
+                continue;
+            }
+            if c.c == open_c {
+                depth += 1;
+            } else if c.c == close_c {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(index);
+                }
+            }
+        }
+        None
+    }
+
+    /// Splits `start..end` at commas not nested within delimiters, trimming whitespace from each
+    /// part and omitting empty parts
+    ///
+    /// If `angle` is true, angle brackets outside of other delimiters are considered delimiters.
+    /// This allows splitting both generic arguments and struct fields whose attributes contain
+    /// comparisons.
+    fn split_top_level(&self, start: usize, end: usize, angle: bool) -> Vec<Range<usize>> {
+        let mut parts = Vec::new();
+        let mut depth = 0usize;
+        let mut angle_depth = 0usize;
+        let mut part_start = start;
+        for index in start..=end {
+            let c = self.chars.get(index).filter(|c| !c.in_string).map(|c| c.c);
+            let follows_arrow = index > 0 && matches!(self.chars[index - 1].c, '-' | '=');
+            match c {
+                Some('(' | '[' | '{') => depth += 1,
+                Some(')' | ']' | '}') => depth = depth.saturating_sub(1),
+                Some('<') if angle && depth == 0 => angle_depth += 1,
+                Some('>') if angle && depth == 0 && !follows_arrow => {
+                    angle_depth = angle_depth.saturating_sub(1);
+                }
+                _ => {}
+            }
+            if index == end || (c == Some(',') && depth == 0 && angle_depth == 0) {
+                if let Some(part) = self.trim(part_start..index) {
+                    parts.push(part);
+                }
+                part_start = index + 1;
+            }
+        }
+        parts
+    }
+
+    fn trim(&self, range: Range<usize>) -> Option<Range<usize>> {
+        let start = range
+            .clone()
+            .find(|&index| !self.chars[index].c.is_whitespace())?;
+        let end = range
+            .rev()
+            .find(|&index| !self.chars[index].c.is_whitespace())?;
+        Some(start..end + 1)
+    }
+
+    fn span(&self, range: &Range<usize>) -> Span {
+        let first = self.chars[range.start];
+        let last = self.chars[range.end - 1];
+        Span {
+            start: (first.line, first.column),
+            end: (
+                last.line,
+                last.column + u32::try_from(last.c.len_utf8()).unwrap_or(1),
+            ),
+        }
+    }
+
+    /// Parses the fields of an `Accounts` struct whose body is `start..end`
+    fn accounts_fields(&self, start: usize, end: usize) -> Vec<AccountsField> {
+        let mut fields = Vec::new();
+        for part in self.split_top_level(start, end, true) {
+            let (attributes, index) = self.attributes(&part);
+            let mut constraints = Vec::new();
+            for (open, close) in attributes {
+                if let Some((name_index, name)) = self.ident(open + 1)
+                    && name == "account"
+                    && let Some(args_open) = self.find(name_index, "(")
+                    && args_open < close
+                    && let Some(args_close) = self.matching(args_open)
+                {
+                    constraints.extend(
+                        self.split_top_level(args_open + 1, args_close, false)
+                            .into_iter()
+                            .map(|range| Constraint {
+                                text: self.text(range.clone()),
+                                span: self.span(&range),
+                            }),
+                    );
+                }
+            }
+            let Some(colon) = self.find(index, ":").filter(|&colon| colon < part.end) else {
+                continue;
+            };
+            let Some((name_index, name)) = self.last_ident(index..colon) else {
+                continue;
+            };
+            fields.push(AccountsField {
+                name,
+                line: self.chars[name_index].line,
+                lines: self.lines(&part),
+                constraints,
+            });
+        }
+        fields
+    }
+
+    /// Parses the variants of an `#[error_code]` enum whose body is `start..end`
+    fn error_variants(&self, start: usize, end: usize) -> Vec<ErrorVariant> {
+        let mut variants = Vec::new();
+        for part in self.split_top_level(start, end, false) {
+            let (_, index) = self.attributes(&part);
+            let Some((name_index, name)) = self.ident(index).filter(|&(i, _)| i < part.end) else {
+                continue;
+            };
+            variants.push(ErrorVariant {
+                name,
+                line: self.chars[name_index].line,
+                lines: self.lines(&part),
+            });
+        }
+        variants
+    }
+
+    /// Returns the `#[...]` attributes at the start of `part`, as pairs of indices of their
+    /// brackets, and the index of what follows them
+    fn attributes(&self, part: &Range<usize>) -> (Vec<(usize, usize)>, usize) {
+        let mut attributes = Vec::new();
+        let mut index = part.start;
+        while index < part.end && self.chars[index].c == '#' {
+            let Some(open) = self.find(index, "[") else {
+                break;
+            };
+            let Some(close) = self.matching(open) else {
+                break;
+            };
+            attributes.push((open, close));
+            index = self
+                .trim(close + 1..part.end)
+                .map_or(part.end, |next| next.start);
+        }
+        (attributes, index)
+    }
+
+    fn lines(&self, range: &Range<usize>) -> RangeInclusive<u32> {
+        self.chars[range.start].line..=self.chars[range.end - 1].line
+    }
+
+    /// Returns the index and text of the last identifier in `range`
+    fn last_ident(&self, range: Range<usize>) -> Option<(usize, String)> {
+        let end = range
+            .clone()
+            .rev()
+            .find(|&index| is_ident_char(self.chars[index].c))?;
+        let start = (range.start..=end)
+            .rev()
+            .take_while(|&index| is_ident_char(self.chars[index].c))
+            .last()?;
+        Some((start, self.text(start..end + 1)))
+    }
+}
+
+/// Returns `contents` with line comments removed, but with its lines otherwise intact
+pub(crate) fn strip_comments(contents: &str) -> String {
+    contents
+        .lines()
+        .map(|line| split_comment(line).0)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Returns the identifiers and keywords in `code`, in order
+pub(crate) fn words(code: &str) -> impl Iterator<Item = &str> {
+    code.split(|c: char| !is_ident_char(c))
+        .filter(|word| !word.is_empty())
+}
+
+pub(crate) fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Splits `line` into its code and line-comment portions
+///
+/// String and character literals are skipped so that a `//` within them does not begin a comment.
+/// Literals that span multiple lines are not handled.
+pub(crate) fn split_comment(line: &str) -> (&str, &str) {
+    let bytes = line.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'"' => i = skip_string(bytes, i),
+            b'\'' => i = skip_char_literal(line, i),
+            b'/' if bytes.get(i + 1) == Some(&b'/') => return line.split_at(i),
+            _ => i += 1,
+        }
+    }
+    (line, "")
+}
+
+/// Returns the index just past the string literal beginning at `start`
+pub(crate) fn skip_string(bytes: &[u8], start: usize) -> usize {
+    let mut i = start + 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'"' => return i + 1,
+            _ => i += 1,
+        }
+    }
+    bytes.len()
+}
+
+/// Returns the index just past the character literal beginning at `start`, or just past the quote
+/// if it begins a lifetime
+pub(crate) fn skip_char_literal(line: &str, start: usize) -> usize {
+    let rest = &line[start + 1..];
+    if let Some(escaped) = rest.strip_prefix('\\') {
+        return escaped
+            .find('\'')
+            .map_or(line.len(), |offset| start + 3 + offset);
+    }
+    let Some(c) = rest.chars().next() else {
+        return line.len();
+    };
+    let close = start + 1 + c.len_utf8();
+    if line.as_bytes().get(close) == Some(&b'\'') {
+        close + 1
+    } else {
+        start + 1
+    }
+}
@@ -0,0 +1,219 @@
+//! Support for running each of a workspace's test suite configurations, i.e., each directory
+//! containing a `Test.toml` file, and comparing their coverage.
+//!
+//! To run configurations concurrently, each one is run from a generated `Test.toml` that extends
+//! the configuration's own and gives the validator its own ports and ledger directory.
+
+use crate::{report::Report, DEFAULT_RPC_PORT};
+use anyhow::Result;
+use std::{
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+/// The comparison table written to the top of the trace directory
+pub const COMPARISON_FILENAME: &str = "test_configs.md";
+
+/// The output of a concurrently run configuration's `anchor test`, written to its trace directory
+pub const ANCHOR_TEST_LOG_FILENAME: &str = "anchor_test.log";
+
+/// `solana-test-validator`'s default faucet port
+pub const DEFAULT_FAUCET_PORT: u16 = 9900;
+
+/// The maximum number of test configurations that can run concurrently
+pub const MAX_JOBS: u16 = 40;
+
+const PORT_STRIDE: u16 = 10;
+const DYNAMIC_PORT_RANGE_START: u16 = 20000;
+const DYNAMIC_PORT_RANGE_LEN: u16 = 1000;
+
+/// Ports for a validator that can run alongside validators using other jobs' ports
+#[derive(Debug, Eq, PartialEq)]
+pub struct ValidatorPorts {
+    pub rpc_port: u16,
+    pub gossip_port: u16,
+    pub faucet_port: u16,
+    pub dynamic_port_range: (u16, u16),
+}
+
+impl ValidatorPorts {
+    /// Returns the ports for job `job`, which must be less than [`MAX_JOBS`]
+    ///
+    /// The RPC and faucet ports are offset from their defaults so that no job collides with a
+    /// validator using the defaults.
+    #[must_use]
+    pub fn for_job(job: u16) -> Self {
+        assert!(job < MAX_JOBS);
+        let rpc_port = DEFAULT_RPC_PORT + PORT_STRIDE * (job + 1);
+        let dynamic_port_range_start = DYNAMIC_PORT_RANGE_START + DYNAMIC_PORT_RANGE_LEN * job;
+        Self {
+            rpc_port,
+            // smoelius: The RPC pubsub service uses `rpc_port + 1`.
+            gossip_port: rpc_port + 2,
+            faucet_port: DEFAULT_FAUCET_PORT + PORT_STRIDE * (job + 1),
+            dynamic_port_range: (
+                dynamic_port_range_start,
+                dynamic_port_range_start + DYNAMIC_PORT_RANGE_LEN - 1,
+            ),
+        }
+    }
+}
+
+/// Returns the directories under `root` containing a `Test.toml` file, relative to `root` and
+/// sorted, as found by Anchor's `TestConfig::discover`
+///
+/// Directories under `trace_dir`, which may hold generated `Test.toml` files, are ignored.
+#[cfg(feature = "__anchor_cli")]
+pub fn discover_test_configs(root: &Path, trace_dir: &Path) -> Result<Vec<PathBuf>> {
+    let Some(test_config) = crate::config::TestConfig::discover(root, Vec::new())? else {
+        return Ok(Vec::new());
+    };
+    let mut dirs = test_config
+        .keys()
+        .filter_map(|path| path.parent())
+        .filter(|dir| !dir.starts_with(trace_dir))
+        .map(|dir| dir.strip_prefix(root).unwrap_or(dir).to_path_buf())
+        .collect::<Vec<_>>();
+    dirs.sort();
+    Ok(dirs)
+}
+
+#[cfg(not(feature = "__anchor_cli"))]
+pub fn discover_test_configs(_root: &Path, _trace_dir: &Path) -> Result<Vec<PathBuf>> {
+    anyhow::bail!("discovering test configurations requires the `__anchor_cli` feature")
+}
+
+/// Removes a generated `Test.toml` on drop
+///
+/// Anchor discovers every `Test.toml` in the workspace, so a generated one left in place would be
+/// run again by a later `anchor test`.
+#[must_use]
+pub struct TestTomlGuard {
+    path: PathBuf,
+}
+
+impl Drop for TestTomlGuard {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Writes to `dir` a `Test.toml` that extends `root`'s test configuration `test_config` and gives
+/// the validator `ports` and `ledger`, and returns a guard that removes the file
+///
+/// If `test_config`'s `Test.toml` has no `[test]` table, the generated file also extends
+/// `Anchor.toml`, so that the validator settings there continue to apply.
+// smoelius: Anchor's types for configurations as written, i.e., before defaults are applied, have
+// underscore-prefixed names.
+#[cfg(feature = "__anchor_cli")]
+#[allow(clippy::used_underscore_items)]
+pub fn write_test_toml(
+    dir: &Path,
+    root: &Path,
+    test_config: &Path,
+    ports: &ValidatorPorts,
+    ledger: &Path,
+) -> Result<TestTomlGuard> {
+    use crate::config::{_TestToml, _TestValidator, _Validator, TestToml};
+
+    let test_toml_path = root.join(test_config).join("Test.toml");
+    let anchor_toml_path = root.join("Anchor.toml");
+
+    let mut extends = Vec::new();
+    if TestToml::from_path(&test_toml_path)?.test.is_none() && anchor_toml_path.is_file() {
+        extends.push(anchor_toml_path.to_string_lossy().into_owned());
+    }
+    extends.push(test_toml_path.to_string_lossy().into_owned());
+
+    let (dynamic_port_range_start, dynamic_port_range_end) = ports.dynamic_port_range;
+    let test_toml = _TestToml {
+        extends: Some(extends),
+        test: Some(_TestValidator {
+            validator: Some(_Validator {
+                rpc_port: Some(ports.rpc_port),
+                gossip_port: Some(ports.gossip_port),
+                faucet_port: Some(ports.faucet_port),
+                dynamic_port_range: Some(format!(
+                    "{dynamic_port_range_start}-{dynamic_port_range_end}"
+                )),
+                ledger: Some(ledger.to_string_lossy().into_owned()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }),
+        scripts: None,
+    };
+
+    // smoelius: Anchor canonicalizes the ledger path when reading `Test.toml`, so the ledger
+    // directory must exist.
+    std::fs::create_dir_all(ledger)?;
+    let path = dir.join("Test.toml");
+    std::fs::write(&path, toml::to_string(&test_toml)?)?;
+
+    Ok(TestTomlGuard { path })
+}
+
+#[cfg(not(feature = "__anchor_cli"))]
+pub fn write_test_toml(
+    _dir: &Path,
+    _root: &Path,
+    _test_config: &Path,
+    _ports: &ValidatorPorts,
+    _ledger: &Path,
+) -> Result<TestTomlGuard> {
+    anyhow::bail!("running test configurations concurrently requires the `__anchor_cli` feature")
+}
+
+/// Returns a Markdown table comparing the line, instruction, error, and event coverage of each
+/// named report
+#[must_use]
+pub fn comparison_table(reports: &[(String, Report)]) -> String {
+    let mut table = String::from(
+        "| Test config | Lines | Instructions | Errors triggered | Events emitted |\n",
+    );
+    table.push_str("| --- | --- | --- | --- | --- |\n");
+    for (name, report) in reports {
+        let lines = report
+            .files
+            .values()
+            .flat_map(|line_hits| line_hits.values().copied());
+        let instructions = report
+            .programs
+            .iter()
+            .flat_map(|program| &program.instructions)
+            .map(|instruction| instruction.executions);
+        let errors = report
+            .programs
+            .iter()
+            .flat_map(|program| &program.errors)
+            .map(|error| error.executions);
+        let events = report
+            .programs
+            .iter()
+            .flat_map(|program| &program.events)
+            .map(|event| event.executions);
+        writeln!(
+            table,
+            "| {name} | {} | {} | {} | {} |",
+            ratio(lines),
+            ratio(instructions),
+            ratio(errors),
+            ratio(events),
+        )
+        .unwrap();
+    }
+    table
+}
+
+/// Formats the number of nonzero `counts` out of the total, e.g., `3/4 (75.0%)`
+fn ratio(counts: impl Iterator<Item = usize>) -> String {
+    let (hit, total) = counts.fold((0_usize, 0_usize), |(hit, total), count| {
+        (hit + usize::from(count > 0), total + 1)
+    });
+    if total == 0 {
+        return String::from("-");
+    }
+    #[allow(clippy::cast_precision_loss)]
+    let percent = hit as f64 * 100.0 / total as f64;
+    format!("{hit}/{total} ({percent:.1}%)")
+}
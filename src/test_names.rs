@@ -0,0 +1,133 @@
+//! Associates program counters files with the tests that produced them.
+//!
+//! Tests announce themselves by appending lines to a marker file, `tests.log`, in the trace
+//! directory. Each line has the form:
+//!
+//! ```text
+//! <milliseconds since the Unix epoch> begin|end <test name>
+//! ```
+//!
+//! A program counters file is attributed to the test whose `begin` and `end` lines surround the
+//! file's modification time. A test with no `end` line is considered to run until the next test's
+//! `begin` line.
+
+use anyhow::{bail, Context, Result};
+use std::{
+    fs::{metadata, read_to_string, OpenOptions},
+    io::Write,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub const TESTS_LOG_FILENAME: &str = "tests.log";
+
+#[derive(Debug)]
+pub(crate) struct TestInterval {
+    pub name: String,
+    pub begin: SystemTime,
+    pub end: Option<SystemTime>,
+}
+
+/// Appends a `begin` line for `name` to the marker file in `sbf_trace_dir`
+pub fn begin_test(sbf_trace_dir: impl AsRef<Path>, name: &str) -> Result<()> {
+    write_marker(sbf_trace_dir.as_ref(), "begin", name)
+}
+
+/// Appends an `end` line for `name` to the marker file in `sbf_trace_dir`
+pub fn end_test(sbf_trace_dir: impl AsRef<Path>, name: &str) -> Result<()> {
+    write_marker(sbf_trace_dir.as_ref(), "end", name)
+}
+
+fn write_marker(sbf_trace_dir: &Path, kind: &str, name: &str) -> Result<()> {
+    let millis = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(sbf_trace_dir.join(TESTS_LOG_FILENAME))?;
+    writeln!(file, "{millis} {kind} {}", name.replace('\n', " "))?;
+    Ok(())
+}
+
+/// Reads the marker file in `sbf_trace_dir`, if one exists
+pub(crate) fn read_test_intervals(sbf_trace_dir: &Path) -> Result<Vec<TestInterval>> {
+    let path = sbf_trace_dir.join(TESTS_LOG_FILENAME);
+    if !path.try_exists()? {
+        return Ok(Vec::new());
+    }
+    let contents = read_to_string(&path)?;
+    parse_test_intervals(&contents)
+        .with_context(|| format!("failed to parse marker file: {}", path.display()))
+}
+
+pub(crate) fn parse_test_intervals(contents: &str) -> Result<Vec<TestInterval>> {
+    let mut test_intervals = Vec::<TestInterval>::new();
+    for (index, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut parts = line.splitn(3, ' ');
+        let (Some(millis), Some(kind), Some(name)) = (parts.next(), parts.next(), parts.next())
+        else {
+            bail!("line {} is malformed: {line:?}", index + 1);
+        };
+        let millis = millis
+            .parse::<u64>()
+            .with_context(|| format!("line {} has an invalid timestamp", index + 1))?;
+        let time = UNIX_EPOCH + Duration::from_millis(millis);
+        match kind {
+            "begin" => test_intervals.push(TestInterval {
+                name: name.to_owned(),
+                begin: time,
+                end: None,
+            }),
+            "end" => {
+                // smoelius: An `end` line without a matching `begin` line is ignored.
+                if let Some(test_interval) = test_intervals
+                    .iter_mut()
+                    .rev()
+                    .find(|test_interval| test_interval.name == name && test_interval.end.is_none())
+                {
+                    test_interval.end = Some(time);
+                }
+            }
+            _ => bail!("line {} has unknown kind `{kind}`", index + 1),
+        }
+    }
+    Ok(test_intervals)
+}
+
+/// Returns the name of the test that produced `pcs_path`, if it can be determined
+pub(crate) fn test_name<'a>(
+    test_intervals: &'a [TestInterval],
+    pcs_path: &Path,
+) -> Result<Option<&'a str>> {
+    if test_intervals.is_empty() {
+        return Ok(None);
+    }
+    let modified = metadata(pcs_path)?.modified()?;
+    Ok(test_at(test_intervals, modified))
+}
+
+pub(crate) fn test_at(test_intervals: &[TestInterval], time: SystemTime) -> Option<&str> {
+    test_intervals
+        .iter()
+        .enumerate()
+        .rev()
+        .find(|&(index, test_interval)| {
+            let end = test_interval.end.or_else(|| {
+                test_intervals
+                    .get(index + 1)
+                    .map(|next_test_interval| next_test_interval.begin)
+            });
+            test_interval.begin <= time && end.is_none_or(|end| time <= end)
+        })
+        .map(|(_, test_interval)| test_interval.name.as_str())
+}
+
+/// Returns `name` with each character other than an ASCII letter, digit, or underscore replaced
+/// with an underscore, as lcov requires of test names
+pub(crate) fn lcov_test_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
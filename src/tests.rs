@@ -1,12 +1,41 @@
-use crate::util::{files_with_extension, patched_agave_tools};
+use crate::{
+    append::{aggregate, next_run_dir, run_dirs},
+    attach::{is_listening, validator_settings, wait_until_ready, wait_until_stopped},
+    build::{
+        build_env, build_fingerprint, build_is_fresh, debug_file_problems, parse_env_var,
+        program_debug_settings, write_build_stamp, DebugLevel,
+    },
+    constraints::{empty_accounts_report, record_trace},
+    coverage_config::load_coverage_config,
+    deploy::stage_programs,
+    doctor::{
+        agave_version, has_line_tables, lockfile_version, overflow_checks_enabled, render,
+        solana_program_dependents, Check, AGAVE_TAG,
+    },
+    exclusions::excluded_lines,
+    instructions::is_handler,
+    report::{Report, REPORT_FILENAME},
+    source::{
+        accounts_structs, emit_sites, error_enums, error_sites, event_structs, handler_contexts,
+        program_module, Span,
+    },
+    test_configs::{comparison_table, discover_test_configs, write_test_toml, ValidatorPorts},
+    test_names::{lcov_test_name, parse_test_intervals, test_at},
+    trace_dir,
+    trace_writer::{record_register_trace, TraceWriter, PC_REGISTER},
+    tracefile::Tracefile,
+    util::{files_with_extension, patched_agave_tools},
+    validator_log::parse_validator_log,
+};
 use anyhow::{anyhow, ensure, Result};
 use std::{
-    collections::HashSet,
+    collections::{BTreeSet, HashSet},
     env::current_dir,
     fs::read_to_string,
     path::{Path, PathBuf},
     process::Command,
-    sync::{LazyLock, Mutex, MutexGuard},
+    sync::{Mutex, MutexGuard},
+    time::{Duration, UNIX_EPOCH},
 };
 
 const SBPF_COVERAGE_DOWNLOAD_URL: &str =
@@ -30,12 +59,6 @@ const EXTERNAL_CALL_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/e
 const MULTIPLE_PROGRAMS_DIR: &str =
     concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/multiple_programs");
 
-static AGAVE_TAG: LazyLock<String> = LazyLock::new(|| {
-    read_to_string("agave_tag.txt")
-        .map(|s| s.trim_end().to_owned())
-        .unwrap()
-});
-
 // smoelius: Only one Anchor test can be run at a time.
 static MUTEX: Mutex<()> = Mutex::new(());
 
@@ -43,10 +66,22 @@ static MUTEX: Mutex<()> = Mutex::new(());
 fn basic() {
     let _lock = prepare_for_testing(BASIC_DIR).unwrap();
 
+    // smoelius: A coverage build must leave the release `.so` files alone.
+    let release_so = Path::new(BASIC_DIR).join("target/deploy/basic.so");
+    std::fs::create_dir_all(release_so.parent().unwrap()).unwrap();
+    std::fs::write(&release_so, "release").unwrap();
+
     let mut command = anchor_coverage_command(BASIC_DIR);
     let status = command.status().unwrap();
     assert!(status.success(), "command failed: {command:?}");
 
+    assert_eq!("release", read_to_string(&release_so).unwrap());
+    std::fs::remove_file(&release_so).unwrap();
+    assert!(Path::new(BASIC_DIR)
+        .join("target/coverage/deploy/basic.debug")
+        .try_exists()
+        .unwrap());
+
     let lcovs = files_with_extension(Path::new(BASIC_DIR).join("sbf_trace_dir"), "lcov").unwrap();
     let source_files = lcovs
         .iter()
@@ -64,6 +99,18 @@ fn basic() {
     // smoelius: Verify lcov was generated.
     let lib_rs_path = Path::new(BASIC_DIR).join("programs/basic/src/lib.rs");
     assert!(source_files.contains(&lib_rs_path));
+
+    // smoelius: Verify `report` keeps the exit code of a failed run.
+    let report_path = Path::new(BASIC_DIR)
+        .join("sbf_trace_dir")
+        .join(REPORT_FILENAME);
+    let mut report = Report::read(&report_path).unwrap();
+    assert_eq!(None, report.test_exit_code);
+    report.test_exit_code = Some(1);
+    report.write(&report_path).unwrap();
+    anchor_coverage_stdout(Path::new(BASIC_DIR), &["report"]);
+    let report = Report::read(&report_path).unwrap();
+    assert_eq!(Some(1), report.test_exit_code);
 }
 
 #[test]
@@ -72,7 +119,7 @@ fn multiple_test_configs() {
 
     for test_config in ["full", "just_increment_x", "just_increment_y"] {
         let mut command = anchor_coverage_command(MULTIPLE_TEST_CONFIGS_DIR);
-        command.args(["--run", &format!("test_configs/{test_config}")]);
+        command.args(["--", "--run", &format!("test_configs/{test_config}")]);
         let status = command.status().unwrap();
         assert!(status.success(), "command failed: {command:?}");
 
@@ -210,6 +257,1115 @@ fn multiple_programs() {
     }
 }
 
+#[test]
+fn exclusion_markers() {
+    let contents = r#"pub fn f(x: u64) -> u64 {
+    if x == 0 {
+        unreachable!(); // coverage:ignore-line
+    }
+    // coverage:ignore-start
+    #[cfg(feature = "mainnet")]
+    msg!("mainnet");
+    // coverage:ignore-end
+    x
+}
+
+#[cfg_attr(coverage_nightly, coverage(off))]
+fn g() {
+    let s = "}"; // "{"
+    let c = '}';
+}
+
+fn h<'a>(s: &'a str) -> &'a str {
+    s // coverage:ignore-start
+"#;
+    assert_eq!(
+        BTreeSet::from([3, 5, 6, 7, 8, 12, 13, 14, 15, 16, 19]),
+        excluded_lines(contents)
+    );
+
+    // smoelius: A semicolon in a signature does not end the item.
+    let contents = r"#[coverage(off)]
+fn f() -> [u8; 4] {
+    [0; 4]
+}
+
+#[coverage(off)]
+fn g<T: Into<[u8; 32]>>(t: T)
+where
+    T: Into<[u8; 32]>,
+{
+    let _ = t;
+}
+
+#[coverage(off)]
+const C: [u8; 4] = [0; 4];
+";
+    assert_eq!(
+        BTreeSet::from([1, 2, 3, 4, 6, 7, 8, 9, 10, 11, 12, 14, 15]),
+        excluded_lines(contents)
+    );
+
+    // smoelius: Other attributes that merely contain `coverage(off)` do not exclude anything.
+    let contents = r#"#[cfg_attr(feature = "x", coverage(off))]
+fn f() {}
+
+#[doc = "coverage(off)"]
+fn g() {}
+"#;
+    assert_eq!(BTreeSet::new(), excluded_lines(contents));
+}
+
+#[test]
+fn instruction_handlers() {
+    let contents = read_to_string(Path::new(BASIC_DIR).join("programs/basic/src/lib.rs")).unwrap();
+    let module = program_module(&contents);
+    assert_eq!(Some("basic"), module.as_deref());
+
+    assert!(is_handler(
+        "basic::basic::initialize",
+        module.as_deref(),
+        "initialize"
+    ));
+    assert!(!is_handler(
+        "basic::basic::increment_x",
+        module.as_deref(),
+        "initialize"
+    ));
+    assert!(!is_handler(
+        "basic::__private::__global::initialize",
+        module.as_deref(),
+        "initialize"
+    ));
+    assert!(!is_handler(
+        "basic::__private::__global::initialize",
+        None,
+        "initialize"
+    ));
+}
+
+#[test]
+fn accounts_constraints() {
+    let contents = read_to_string(Path::new(BASIC_DIR).join("programs/basic/src/lib.rs")).unwrap();
+    assert_eq!(
+        vec![
+            (String::from("initialize"), String::from("Initialize")),
+            (String::from("increment_x"), String::from("IncrementX")),
+            (String::from("increment_y"), String::from("IncrementY")),
+        ],
+        handler_contexts(&contents)
+    );
+
+    let contents = r#"#[derive(Accounts)]
+#[instruction(amount: u64)]
+pub struct Withdraw<'info> {
+    #[account(
+        mut,
+        has_one = authority @ VaultError::Unauthorized,
+        constraint = vault.amount >= amount, // "comment, with comma"
+        seeds = [b"vault", authority.key().as_ref()],
+        bump,
+    )]
+    pub vault: Account<'info, Vault>,
+
+    /// CHECK: Only used as a signer.
+    pub authority: Signer<'info>,
+}
+"#;
+    let [withdraw] = accounts_structs(contents).try_into().unwrap();
+    assert_eq!("Withdraw", withdraw.name);
+    assert_eq!(3, withdraw.line);
+    let [vault, authority] = withdraw.fields.try_into().unwrap();
+    assert_eq!(
+        ("vault", 11, 4..=11),
+        (vault.name.as_str(), vault.line, vault.lines)
+    );
+    assert_eq!(
+        vec![
+            "mut",
+            "has_one = authority @ VaultError::Unauthorized",
+            "constraint = vault.amount >= amount",
+            r#"seeds = [b"vault", authority.key().as_ref()]"#,
+            "bump",
+        ],
+        vault
+            .constraints
+            .iter()
+            .map(|constraint| constraint.text.as_str())
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        Span {
+            start: (6, 9),
+            end: (6, 55)
+        },
+        vault.constraints[1].span
+    );
+    assert!(vault.constraints[1].span.contains(6, 19));
+    assert!(!vault.constraints[1].span.contains(7, 9));
+    assert_eq!(
+        ("authority", 14, 14..=14),
+        (authority.name.as_str(), authority.line, authority.lines)
+    );
+    assert!(authority.constraints.is_empty());
+
+    // smoelius: A constraint checked last is considered to have failed only if validation did not
+    // continue to a later field.
+    let [withdraw] = accounts_structs(contents).try_into().unwrap();
+    let constraint = crate::Entry {
+        file: "lib.rs",
+        line: 6,
+        column: 19,
+    };
+    let later_field = crate::Entry {
+        file: "lib.rs",
+        line: 14,
+        column: 5,
+    };
+    for (entries, failed, failures) in [
+        (vec![&constraint], true, 1),
+        (vec![&constraint, &later_field], true, 0),
+        (vec![&constraint], false, 0),
+    ] {
+        let mut report = empty_accounts_report("lib.rs", &withdraw, true);
+        record_trace(&withdraw, &mut report, &entries, failed);
+        assert_eq!(1, report.fields[0].constraints[1].executions);
+        assert_eq!(Some(failures), report.fields[0].constraints[1].failures);
+    }
+}
+
+#[test]
+fn error_codes_and_sites() {
+    let contents = r#"pub fn withdraw(ctx: Context<Withdraw>, amount: u64) -> Result<()> {
+    require!(amount > 0, VaultError::ZeroAmount);
+    require_keys_eq!(
+        ctx.accounts.vault.authority,
+        ctx.accounts.authority.key(),
+        VaultError::Unauthorized
+    );
+    require_gte!(ctx.accounts.vault.amount, amount);
+    if amount == u64::MAX {
+        return err!(VaultError::Overflow);
+    }
+    Ok(())
+}
+
+#[error_code]
+pub enum VaultError {
+    #[msg("Amount must be nonzero")]
+    ZeroAmount,
+    Unauthorized,
+    #[msg("Overflow, of a sort")]
+    Overflow,
+}
+"#;
+    let [vault_error] = error_enums(contents).try_into().unwrap();
+    assert_eq!(
+        ("VaultError", 15..=22),
+        (vault_error.name.as_str(), vault_error.lines)
+    );
+    assert_eq!(
+        vec![
+            ("ZeroAmount", 18, 17..=18),
+            ("Unauthorized", 19, 19..=19),
+            ("Overflow", 21, 20..=21),
+        ],
+        vault_error
+            .variants
+            .iter()
+            .map(|variant| (variant.name.as_str(), variant.line, variant.lines.clone()))
+            .collect::<Vec<_>>()
+    );
+
+    assert_eq!(
+        vec![
+            ("require", Some("VaultError::ZeroAmount"), 2..=2),
+            ("require_keys_eq", Some("VaultError::Unauthorized"), 3..=7),
+            ("require_gte", None, 8..=8),
+            ("err", Some("VaultError::Overflow"), 10..=10),
+        ],
+        error_sites(contents)
+            .iter()
+            .map(|error_site| (
+                error_site.macro_name.as_str(),
+                error_site.error.as_deref(),
+                error_site.lines.clone()
+            ))
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn events_and_emit_sites() {
+    let contents = r"pub fn deposit(ctx: Context<Deposit>, amount: u64) -> Result<()> {
+    emit!(Deposited { amount });
+    emit_cpi!(events::Deposited {
+        amount,
+    });
+    let event = Withdrawn { amount };
+    emit!(event);
+    Ok(())
+}
+
+#[event]
+pub struct Deposited {
+    pub amount: u64,
+}
+
+#[event]
+#[derive(Debug)]
+pub struct Withdrawn {
+    pub amount: u64,
+}
+";
+    assert_eq!(
+        vec![("Deposited", 12), ("Withdrawn", 18)],
+        event_structs(contents)
+            .iter()
+            .map(|event_struct| (event_struct.name.as_str(), event_struct.line))
+            .collect::<Vec<_>>()
+    );
+
+    assert_eq!(
+        vec![
+            ("emit", Some("Deposited"), 2..=2),
+            ("emit_cpi", Some("Deposited"), 3..=5),
+            ("emit", None, 7..=7),
+        ],
+        emit_sites(contents)
+            .iter()
+            .map(|emit_site| (
+                emit_site.macro_name.as_str(),
+                emit_site.event.as_deref(),
+                emit_site.lines.clone()
+            ))
+            .collect::<Vec<_>>()
+    );
+
+    assert_eq!(
+        vec![(Some("Deposited"), 1..=1)],
+        emit_sites("emit!(events::Deposited::<u64> { amount });\n")
+            .iter()
+            .map(|emit_site| (emit_site.event.as_deref(), emit_site.lines.clone()))
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_attribution() {
+    let contents = "\
+1000 begin basic Is initialized!
+1500 end basic Is initialized!
+2000 begin basic Should increment `x`
+3000 begin basic Should increment `y`
+3500 end basic Should increment `y`
+";
+    let test_intervals = parse_test_intervals(contents).unwrap();
+    let at = |millis| test_at(&test_intervals, UNIX_EPOCH + Duration::from_millis(millis));
+    assert_eq!(None, at(999));
+    assert_eq!(Some("basic Is initialized!"), at(1000));
+    assert_eq!(Some("basic Is initialized!"), at(1500));
+    assert_eq!(None, at(1750));
+    assert_eq!(Some("basic Should increment `x`"), at(2500));
+    assert_eq!(Some("basic Should increment `y`"), at(3000));
+    assert_eq!(None, at(4000));
+
+    assert!(parse_test_intervals("1000 start basic").is_err());
+
+    assert_eq!(
+        "basic_Should_increment__x_",
+        lcov_test_name("basic Should increment `x`")
+    );
+}
+
+#[test]
+fn validator_log_invocations() {
+    const SIGNATURE: &str =
+        "5VERv8NMvzbJMEkV8xnrLkEaWRtSz9CosKDYjCJjBRnbJLgp8uirBgmQpjKhoR4tjF3ZpRzrFmBV6UjKdiSZkQUW";
+    const PROGRAM_ID: &str = "Fg6PaFpoGXkYsidMpWTK6W2BeZ7FEfcYkg476zPFsLnS";
+    const SYSTEM_PROGRAM_ID: &str = "11111111111111111111111111111111";
+    const PREFIX: &str =
+        "[2025-01-01T00:00:00.000000000Z DEBUG solana_runtime::message_processor::stable_log]";
+
+    let contents = format!(
+        "\
+[2025-01-01T00:00:00.000000000Z DEBUG solana_rpc::rpc] transaction {SIGNATURE}
+{PREFIX} Program {PROGRAM_ID} invoke [1]
+{PREFIX} Program log: Instruction: Initialize
+{PREFIX} Program {SYSTEM_PROGRAM_ID} invoke [2]
+{PREFIX} Program {SYSTEM_PROGRAM_ID} success
+{PREFIX} Program {PROGRAM_ID} consumed 5000 of 200000 compute units
+{PREFIX} Program {PROGRAM_ID} success
+{PREFIX} Program {PROGRAM_ID} invoke [1]
+{PREFIX} Program log: AnchorError occurred.
+{PREFIX} Program {PROGRAM_ID} consumed 2500 of 195000 compute units
+{PREFIX} Program {PROGRAM_ID} failed: custom program error: 0x1770
+{PREFIX} Program {PROGRAM_ID} invoke [1]
+"
+    );
+
+    let invocations = parse_validator_log(&contents);
+    assert_eq!(
+        vec![
+            (PROGRAM_ID, Some(0), 1, Some(5000), Some(true), None),
+            (SYSTEM_PROGRAM_ID, None, 2, None, Some(true), None),
+            (
+                PROGRAM_ID,
+                Some(1),
+                1,
+                Some(2500),
+                Some(false),
+                Some("custom program error: 0x1770")
+            ),
+            (PROGRAM_ID, Some(2), 1, None, None, None),
+        ],
+        invocations
+            .iter()
+            .map(|invocation| (
+                invocation.program_id.as_str(),
+                invocation.instruction_index,
+                invocation.stack_height,
+                invocation.compute_units,
+                invocation.success,
+                invocation.error.as_deref()
+            ))
+            .collect::<Vec<_>>()
+    );
+    assert!(invocations
+        .iter()
+        .all(|invocation| invocation.signature.as_deref() == Some(SIGNATURE)));
+}
+
+#[test]
+fn stale_lcov_files() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let dir = tempdir.path();
+
+    for file_name in [
+        "1234.pcs",
+        "1234.lcov",
+        "1234.5VERv8NM.success.lcov",
+        "12345.lcov",
+        "coverage.lcov",
+    ] {
+        std::fs::write(dir.join(file_name), "").unwrap();
+    }
+
+    crate::remove_lcov_files(&[dir.join("1234.pcs")]).unwrap();
+
+    assert_eq!(
+        ["12345.lcov", "coverage.lcov"]
+            .into_iter()
+            .map(|file_name| dir.join(file_name))
+            .collect::<BTreeSet<_>>(),
+        files_with_extension(dir, "lcov")
+            .unwrap()
+            .into_iter()
+            .collect::<BTreeSet<_>>()
+    );
+}
+
+#[test]
+fn tracefile_merge() {
+    let mut tracefile = "\
+TN:shard_1
+SF:/ci/shard_1/programs/basic/src/lib.rs
+FN:8,basic::initialize
+FNDA:1,basic::initialize
+DA:8,1
+DA:9,0
+BRDA:9,0,0,-
+LF:2
+LH:1
+end_of_record
+"
+    .parse::<Tracefile>()
+    .unwrap()
+    .remap(|path| Path::new("/w").join(path.strip_prefix("/ci/shard_1").unwrap()));
+
+    let other = "\
+TN:shard_1
+SF:/w/programs/basic/src/lib.rs
+DA:9,2
+BRDA:9,0,0,2
+end_of_record
+"
+    .parse::<Tracefile>()
+    .unwrap();
+
+    tracefile.merge(other);
+
+    assert_eq!(
+        "\
+TN:shard_1
+SF:/w/programs/basic/src/lib.rs
+FN:8,basic::initialize
+FNDA:1,basic::initialize
+FNF:1
+FNH:1
+BRDA:9,0,0,2
+BRF:1
+BRH:1
+DA:8,1
+DA:9,2
+LF:2
+LH:2
+end_of_record
+",
+        tracefile.to_string()
+    );
+
+    assert!("SF:lib.rs\nDA:1\nend_of_record\n"
+        .parse::<Tracefile>()
+        .is_err());
+}
+
+#[test]
+fn tracefile_lcov_2_functions() {
+    let tracefile = "\
+SF:/w/programs/basic/src/lib.rs
+FN:8,12,basic::initialize
+FN:14,<basic::Foo<u8,u16>>::new
+FNDA:1,basic::initialize
+FNDA:0,<basic::Foo<u8,u16>>::new
+FNDA:2,basic::initialize
+end_of_record
+"
+    .parse::<Tracefile>()
+    .unwrap();
+
+    let record = tracefile.records.values().next().unwrap();
+    let function = &record.functions["basic::initialize"];
+    assert_eq!(
+        (Some(8), Some(12), 3),
+        (function.line, function.end_line, function.hits)
+    );
+    let function = &record.functions["<basic::Foo<u8,u16>>::new"];
+    assert_eq!(
+        (Some(14), None, 0),
+        (function.line, function.end_line, function.hits)
+    );
+
+    assert_eq!(
+        "\
+SF:/w/programs/basic/src/lib.rs
+FN:14,<basic::Foo<u8,u16>>::new
+FN:8,12,basic::initialize
+FNDA:0,<basic::Foo<u8,u16>>::new
+FNDA:3,basic::initialize
+FNF:2
+FNH:1
+LF:0
+LH:0
+end_of_record
+",
+        tracefile.to_string()
+    );
+}
+
+#[test]
+fn append_aggregate() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let trace_dir = tempdir.path();
+
+    for (lcov, hits, test_exit_code) in [("DA:8,1\nDA:9,0\n", 1, None), ("DA:9,2\n", 2, Some(1))] {
+        let run_dir = next_run_dir(trace_dir).unwrap();
+        std::fs::create_dir(&run_dir).unwrap();
+        std::fs::write(
+            run_dir.join("basic.lcov"),
+            format!("SF:/w/lib.rs\n{lcov}end_of_record\n"),
+        )
+        .unwrap();
+        Report {
+            files: [(PathBuf::from("/w/lib.rs"), [(9, hits)].into())].into(),
+            test_exit_code,
+            ..Report::default()
+        }
+        .write(run_dir.join("coverage.json"))
+        .unwrap();
+    }
+
+    assert_eq!(
+        [trace_dir.join("run-1"), trace_dir.join("run-2")],
+        run_dirs(trace_dir).unwrap().as_slice()
+    );
+    assert_eq!(trace_dir.join("run-3"), next_run_dir(trace_dir).unwrap());
+
+    aggregate(trace_dir).unwrap();
+
+    assert_eq!(
+        "SF:/w/lib.rs\nDA:8,1\nDA:9,2\nLF:2\nLH:2\nend_of_record\n",
+        read_to_string(trace_dir.join("coverage.lcov")).unwrap()
+    );
+    let report = Report::read(trace_dir.join("coverage.json")).unwrap();
+    assert_eq!(Some(&3), report.files[Path::new("/w/lib.rs")].get(&9));
+    assert_eq!(Some(1), report.test_exit_code);
+    assert_eq!(
+        [None, Some(1)],
+        [1, 2]
+            .map(|n| Report::recorded_test_exit_code(&trace_dir.join(format!("run-{n}"))).unwrap())
+    );
+    assert_eq!(
+        None,
+        Report::recorded_test_exit_code(&trace_dir.join("run-3")).unwrap()
+    );
+
+    std::fs::write(trace_dir.join("basic.pcs"), []).unwrap();
+    assert!(next_run_dir(trace_dir).is_err());
+}
+
+#[test]
+fn clean_trace_dir() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let root = tempdir.path();
+    let trace_dir = root.join("sbf_trace_dir");
+    let run_dir = trace_dir.join("run-1");
+    let config_dir = trace_dir.join("tests/config_a");
+    std::fs::create_dir_all(&run_dir).unwrap();
+    std::fs::create_dir_all(&config_dir).unwrap();
+    for path in [
+        run_dir.join("1234.pcs"),
+        run_dir.join("1234.insns"),
+        run_dir.join("1234.5VERv8NM.success.lcov"),
+        config_dir.join("coverage.json"),
+        config_dir.join("anchor_test.log"),
+        trace_dir.join("coverage.lcov"),
+        trace_dir.join("notes.txt"),
+    ] {
+        std::fs::write(path, "").unwrap();
+    }
+
+    assert!(trace_dir::clean(root, root).is_err());
+    assert!(trace_dir::clean(root.parent().unwrap(), root).is_err());
+    assert!(run_dir.join("1234.pcs").exists());
+
+    trace_dir::clean(&trace_dir, root).unwrap();
+    assert_eq!(
+        vec![trace_dir.join("notes.txt")],
+        std::fs::read_dir(&trace_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>()
+    );
+
+    std::fs::remove_file(trace_dir.join("notes.txt")).unwrap();
+    trace_dir::clean(&trace_dir, root).unwrap();
+    assert!(!trace_dir.exists());
+}
+
+#[test]
+fn test_configs() {
+    let root = Path::new(MULTIPLE_TEST_CONFIGS_DIR);
+    let test_configs = discover_test_configs(root, &root.join("sbf_trace_dir")).unwrap();
+    assert_eq!(
+        [
+            "test_configs/full",
+            "test_configs/just_increment_x",
+            "test_configs/just_increment_y"
+        ]
+        .map(PathBuf::from),
+        test_configs.as_slice()
+    );
+
+    let report = Report {
+        files: [(PathBuf::from("lib.rs"), [(8, 1), (9, 0), (10, 2)].into())].into(),
+        ..Report::default()
+    };
+    assert_eq!(
+        "| Test config | Lines | Instructions | Errors triggered | Events emitted |
+| --- | --- | --- | --- | --- |
+| full | 2/3 (66.7%) | - | - | - |
+",
+        comparison_table(&[(String::from("full"), report)])
+    );
+}
+
+#[test]
+fn concurrent_test_config() {
+    let root = Path::new(MULTIPLE_TEST_CONFIGS_DIR);
+    let tempdir = tempfile::tempdir().unwrap();
+    let ledger = tempdir.path().join("test-ledger");
+
+    let ports = ValidatorPorts::for_job(1);
+    assert_eq!(
+        ValidatorPorts {
+            rpc_port: 8919,
+            gossip_port: 8921,
+            faucet_port: 9920,
+            dynamic_port_range: (21000, 21999),
+        },
+        ports
+    );
+    assert!(ValidatorPorts::for_job(0).dynamic_port_range.1 < ports.dynamic_port_range.0);
+
+    let guard = write_test_toml(
+        tempdir.path(),
+        root,
+        Path::new("test_configs/full"),
+        &ports,
+        &ledger,
+    )
+    .unwrap();
+
+    let test_toml = crate::config::TestToml::from_path(tempdir.path().join("Test.toml")).unwrap();
+    let validator = test_toml.test.unwrap().validator.unwrap();
+    assert_eq!(8919, validator.rpc_port);
+    assert_eq!(Some(8921), validator.gossip_port);
+    assert_eq!(Some(9920), validator.faucet_port);
+    assert_eq!(ledger.to_string_lossy(), validator.ledger);
+    assert!(test_toml.scripts["test"].ends_with("tests/full.ts"));
+
+    drop(guard);
+    assert!(!tempdir.path().join("Test.toml").exists());
+}
+
+#[test]
+fn attach_validator() {
+    let root = Path::new(BASIC_DIR);
+    let settings = validator_settings(root).unwrap();
+    assert_eq!(crate::DEFAULT_RPC_PORT, settings.rpc_port);
+    assert_eq!(Duration::from_secs(5), settings.startup_wait);
+    assert_eq!(root.join(".anchor/test-ledger"), settings.ledger);
+
+    let command = settings.solana_test_validator_command(Path::new("/traces"));
+    let args = command
+        .get_args()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        [
+            "--bpf-program",
+            "Fg6PaFpoGXkYsidMpWTK6W2BeZ7FEfcYkg476zPFsLnS",
+            &format!("{BASIC_DIR}/target/coverage/deploy/basic.so")
+        ],
+        &args[args.len() - 3..]
+    );
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    wait_until_ready(port, Some(Duration::ZERO)).unwrap();
+    drop(listener);
+    wait_until_stopped(port);
+    assert!(!is_listening(port));
+    assert!(wait_until_ready(port, Some(Duration::ZERO)).is_err());
+}
+
+#[test]
+fn trace_writer() {
+    use byteorder::{LittleEndian, ReadBytesExt};
+
+    let tempdir = tempfile::tempdir().unwrap();
+
+    // smoelius: A synthetic text section of four instructions, each with a distinct opcode.
+    let text = (0..4_u8)
+        .flat_map(|i| [0x07, i, 0, 0, i, 0, 0, 0])
+        .collect::<Vec<_>>();
+    let register_trace = [0, 1, 3, 1]
+        .map(|pc| std::array::from_fn::<_, 12, _>(|i| if i == PC_REGISTER { pc } else { 0 }));
+
+    let mut trace_writer = TraceWriter::create(tempdir.path()).unwrap();
+    record_register_trace(&mut trace_writer, &register_trace, &text).unwrap();
+    let pcs_path = trace_writer.finish().unwrap().unwrap();
+
+    assert_eq!(vec![0, 8, 24, 8], crate::read_vaddrs(&pcs_path).unwrap());
+    let mut insns_file = std::fs::File::open(pcs_path.with_extension("insns")).unwrap();
+    for pc in [0, 1, 3, 1] {
+        let offset = pc * 8;
+        let expected = u64::from_le_bytes(text[offset..offset + 8].try_into().unwrap());
+        assert_eq!(expected, insns_file.read_u64::<LittleEndian>().unwrap());
+    }
+    assert!(insns_file.read_u64::<LittleEndian>().is_err());
+
+    let mut trace_writer = TraceWriter::create(tempdir.path()).unwrap();
+    assert_ne!(pcs_path, trace_writer.pcs_path());
+    assert!(record_register_trace(&mut trace_writer, &[[4; 12]], &text).is_err());
+
+    let trace_writer = TraceWriter::create(tempdir.path()).unwrap();
+    let empty_pcs_path = trace_writer.pcs_path().to_path_buf();
+    assert!(trace_writer.finish().unwrap().is_none());
+    assert!(!empty_pcs_path.try_exists().unwrap());
+}
+
+#[test]
+fn doctor() {
+    assert_eq!(
+        AGAVE_TAG.trim_end().strip_prefix('v'),
+        Some(agave_version())
+    );
+
+    let cargo_lock = "version = 4
+
+[[package]]
+name = \"anchor-lang\"
+version = \"0.30.1\"
+
+[[package]]
+name = \"anchor-lang\"
+version = \"0.31.1\"
+
+[[package]]
+name = \"anchor-lang-idl\"
+version = \"0.1.2\"
+";
+    assert_eq!(
+        Some("0.31.1"),
+        lockfile_version(cargo_lock, "anchor-lang")
+            .unwrap()
+            .as_deref()
+    );
+    assert_eq!(None, lockfile_version(cargo_lock, "borsh").unwrap());
+
+    assert!(overflow_checks_enabled("[profile.release]\noverflow-checks = true\n").unwrap());
+    assert!(!overflow_checks_enabled("[profile.release]\nlto = \"fat\"\n").unwrap());
+    assert!(!overflow_checks_enabled("[workspace]\n").unwrap());
+    assert!(solana_program_dependents(Path::new(BASIC_DIR))
+        .unwrap()
+        .is_empty());
+
+    assert_eq!(
+        "[PASS] A: ok\n[FAIL] B: broken\n       Fix: repair it\n",
+        render(&[
+            Check::pass("A", "ok"),
+            Check::fail("B", "broken", "repair it")
+        ])
+    );
+
+    // smoelius: The test binary is built with debug information.
+    #[cfg(target_os = "linux")]
+    assert!(has_line_tables(&std::env::current_exe().unwrap()).unwrap());
+    assert!(has_line_tables(Path::new("Cargo.toml")).is_err());
+}
+
+#[test]
+fn coverage_build_env() {
+    let root = Path::new("/w");
+
+    let env = build_env(root, Some("1")).unwrap();
+    let get = |key: &str| {
+        env.iter()
+            .find(|&&(other, _)| other == key)
+            .map(|(_, value)| value.to_string_lossy().into_owned())
+    };
+    assert_eq!(
+        Some("/w/target/coverage"),
+        get("CARGO_TARGET_DIR").as_deref()
+    );
+    assert_eq!(Some("true"), get("CARGO_PROFILE_RELEASE_DEBUG").as_deref());
+    assert_eq!(
+        Some("/w/target/coverage/deploy"),
+        get("SBF_OUT_PATH").as_deref()
+    );
+    assert_eq!(Some("1"), get("CARGO_PROFILE_RELEASE_OPT_LEVEL").as_deref());
+
+    assert!(build_env(root, None)
+        .unwrap()
+        .iter()
+        .all(|&(key, _)| key != "CARGO_PROFILE_RELEASE_OPT_LEVEL"));
+    assert!(build_env(root, Some("fast")).is_err());
+
+    assert_eq!(("KEY", "a=b"), parse_env_var("KEY=a=b").unwrap());
+    assert_eq!(("KEY", ""), parse_env_var("KEY=").unwrap());
+    assert!(parse_env_var("KEY").is_err());
+    assert!(parse_env_var("=value").is_err());
+}
+
+#[test]
+fn program_debug() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let root = tempdir.path();
+
+    std::fs::write(
+        root.join("Cargo.toml"),
+        "[workspace]
+members = [\"programs/*\"]
+resolver = \"2\"
+
+[profile.release]
+debug = false
+
+[profile.release.package.alpha]
+debug = \"line-directives-only\"
+",
+    )
+    .unwrap();
+    for name in ["alpha", "beta", "gamma"] {
+        let dir = root.join("programs").join(name);
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(
+            dir.join("Cargo.toml"),
+            format!(
+                "[package]
+name = \"{name}\"
+version = \"0.1.0\"
+edition = \"2021\"
+
+[lib]
+crate-type = [\"cdylib\", \"lib\"]
+"
+            ),
+        )
+        .unwrap();
+        std::fs::write(dir.join("src/lib.rs"), "").unwrap();
+    }
+    let cargo_dir = root.join("programs/beta/.cargo");
+    std::fs::create_dir(&cargo_dir).unwrap();
+    std::fs::write(
+        cargo_dir.join("config.toml"),
+        "[profile.release]\nstrip = \"debuginfo\"\n",
+    )
+    .unwrap();
+
+    let programs = program_debug_settings(root).unwrap();
+    let summary = programs
+        .iter()
+        .map(|program| (program.lib_name.as_str(), program.has_line_tables()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        [("alpha", false), ("beta", false), ("gamma", true)],
+        *summary
+    );
+    assert_eq!(DebugLevel::LineDirectivesOnly, programs[0].debug);
+    assert!(programs[0]
+        .problem()
+        .unwrap()
+        .contains("[profile.release.package.alpha]"));
+    assert!(programs[1].problem().unwrap().contains("strip"));
+    assert_eq!(None, programs[2].problem());
+
+    let problems = debug_file_problems(&programs[2..], &root.join("target/deploy")).unwrap();
+    assert_eq!(1, problems.len());
+    assert!(problems[0].contains("has no debug file"));
+
+    for (value, level) in [
+        ("2", DebugLevel::Full),
+        ("\"full\"", DebugLevel::Full),
+        ("1", DebugLevel::Limited),
+        ("\"line-tables-only\"", DebugLevel::LineTablesOnly),
+        ("false", DebugLevel::None),
+    ] {
+        let table = format!("debug = {value}").parse::<toml::Table>().unwrap();
+        assert_eq!(Some(level), DebugLevel::parse(&table["debug"]));
+    }
+}
+
+#[test]
+fn build_stamp() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let root = tempdir.path();
+
+    std::fs::write(
+        root.join("Cargo.toml"),
+        "[package]
+name = \"alpha\"
+version = \"0.1.0\"
+edition = \"2021\"
+
+[lib]
+crate-type = [\"cdylib\", \"lib\"]
+",
+    )
+    .unwrap();
+    std::fs::create_dir(root.join("src")).unwrap();
+    std::fs::write(root.join("src/lib.rs"), "").unwrap();
+
+    let fingerprint = build_fingerprint(root, &["anchor"]).unwrap();
+    assert_eq!(fingerprint, build_fingerprint(root, &["anchor"]).unwrap());
+    assert_ne!(fingerprint, build_fingerprint(root, &["native"]).unwrap());
+
+    // smoelius: Files outside of the packages' sources, e.g., traces, do not affect the
+    // fingerprint.
+    std::fs::create_dir(root.join("sbf_trace_dir")).unwrap();
+    std::fs::write(root.join("sbf_trace_dir/0.pcs"), "").unwrap();
+    assert_eq!(fingerprint, build_fingerprint(root, &["anchor"]).unwrap());
+
+    // smoelius: A build that wrote no debug files is not fresh.
+    write_build_stamp(root, &fingerprint).unwrap();
+    assert!(!build_is_fresh(root, &fingerprint).unwrap());
+
+    let deploy_dir = root.join("target/coverage/deploy");
+    std::fs::create_dir_all(&deploy_dir).unwrap();
+    std::fs::write(deploy_dir.join("alpha.so"), "so").unwrap();
+    std::fs::write(deploy_dir.join("alpha.debug"), "debug").unwrap();
+    write_build_stamp(root, &fingerprint).unwrap();
+    assert!(build_is_fresh(root, &fingerprint).unwrap());
+
+    // smoelius: An ordinary `anchor build` writes to `target/deploy`, which coverage builds do not
+    // use.
+    std::fs::create_dir_all(root.join("target/deploy")).unwrap();
+    std::fs::write(root.join("target/deploy/alpha.so"), "release so").unwrap();
+    assert!(build_is_fresh(root, &fingerprint).unwrap());
+
+    std::fs::write(deploy_dir.join("alpha.so"), "rebuilt so").unwrap();
+    assert!(!build_is_fresh(root, &fingerprint).unwrap());
+    write_build_stamp(root, &fingerprint).unwrap();
+    assert!(build_is_fresh(root, &fingerprint).unwrap());
+
+    std::fs::write(root.join("src/lib.rs"), "// changed\n").unwrap();
+    let changed = build_fingerprint(root, &["anchor"]).unwrap();
+    assert_ne!(fingerprint, changed);
+    assert!(!build_is_fresh(root, &changed).unwrap());
+}
+
+#[test]
+fn stage_coverage_programs() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let root = tempdir.path();
+    let coverage_deploy_dir = root.join("target/coverage/deploy");
+    let deploy_dir = root.join("target/deploy");
+    std::fs::create_dir_all(&coverage_deploy_dir).unwrap();
+    std::fs::create_dir_all(&deploy_dir).unwrap();
+    std::fs::write(coverage_deploy_dir.join("alpha.so"), "coverage alpha").unwrap();
+    std::fs::write(coverage_deploy_dir.join("beta.so"), "coverage beta").unwrap();
+    std::fs::write(deploy_dir.join("alpha.so"), "release alpha").unwrap();
+    let modified = || {
+        std::fs::metadata(deploy_dir.join("alpha.so"))
+            .unwrap()
+            .modified()
+            .unwrap()
+    };
+    let release_modified = modified();
+
+    let staged = stage_programs(root).unwrap();
+    assert_eq!(
+        "coverage alpha",
+        read_to_string(deploy_dir.join("alpha.so")).unwrap()
+    );
+    assert_eq!(
+        "coverage beta",
+        read_to_string(deploy_dir.join("beta.so")).unwrap()
+    );
+    drop(staged);
+    assert_eq!(
+        "release alpha",
+        read_to_string(deploy_dir.join("alpha.so")).unwrap()
+    );
+    assert_eq!(release_modified, modified());
+    assert!(!deploy_dir.join("beta.so").try_exists().unwrap());
+
+    // smoelius: The release file of an interrupted run is restored by the next run.
+    std::mem::forget(stage_programs(root).unwrap());
+    drop(stage_programs(root).unwrap());
+    assert_eq!(
+        "release alpha",
+        read_to_string(deploy_dir.join("alpha.so")).unwrap()
+    );
+
+    // smoelius: Unless `target/deploy` was rebuilt since.
+    std::mem::forget(stage_programs(root).unwrap());
+    std::fs::write(deploy_dir.join("alpha.so"), "rebuilt alpha").unwrap();
+    drop(stage_programs(root).unwrap());
+    assert_eq!(
+        "rebuilt alpha",
+        read_to_string(deploy_dir.join("alpha.so")).unwrap()
+    );
+}
+
+#[test]
+fn coverage_config() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let root = tempdir.path();
+
+    let anchor_toml = read_to_string(Path::new(BASIC_DIR).join("Anchor.toml")).unwrap();
+    std::fs::write(
+        root.join("Anchor.toml"),
+        format!(
+            "{anchor_toml}
+[coverage]
+anchor_test_args = [\"--skip-local-validator\"]
+debug_dir = \"target/coverage\"
+"
+        ),
+    )
+    .unwrap();
+
+    let loaded = load_coverage_config(root).unwrap();
+    assert_eq!(Some(root.join("Anchor.toml")), loaded.path);
+    assert_eq!(
+        ["--skip-local-validator"],
+        loaded.config.anchor_test_args.as_slice()
+    );
+    assert_eq!(Some(root.join("target/coverage")), loaded.config.debug_dir);
+    assert_eq!(root.join("sbf_trace_dir"), loaded.config.trace_dir(root));
+    assert_eq!(
+        "[coverage]\nanchor_test_args = [\"--skip-local-validator\"]\ndebug_dir = \
+         \"{}/target/coverage\"\n",
+        loaded
+            .config
+            .to_toml()
+            .unwrap()
+            .replace(&*root.to_string_lossy(), "{}")
+    );
+
+    // smoelius: The workspace root, and thus the settings, are found from a subdirectory.
+    let subdir = root.join("programs");
+    std::fs::create_dir(&subdir).unwrap();
+    let canonical_root = std::fs::canonicalize(root).unwrap();
+    assert_eq!(
+        "# Read from: {}/Anchor.toml\n[coverage]\nanchor_test_args = \
+         [\"--skip-local-validator\"]\ndebug_dir = \"{}/target/coverage\"\n",
+        anchor_coverage_stdout(&subdir, &["--print-config"])
+            .replace(&*canonical_root.to_string_lossy(), "{}")
+    );
+
+    std::fs::write(root.join("anchor-coverage.toml"), "remap = [\"/ci=/w\"]\n").unwrap();
+    assert!(load_coverage_config(root).is_err());
+
+    std::fs::write(root.join("Anchor.toml"), anchor_toml).unwrap();
+    let loaded = load_coverage_config(root).unwrap();
+    assert_eq!(Some(root.join("anchor-coverage.toml")), loaded.path);
+    assert_eq!(["/ci=/w"], loaded.config.remap.as_slice());
+
+    // smoelius: A native workspace has no `Anchor.toml`.
+    std::fs::remove_file(root.join("Anchor.toml")).unwrap();
+    std::fs::write(
+        root.join("anchor-coverage.toml"),
+        "native_test_command = [\"cargo\", \"test\"]\nbuild_sbf_args = [\"--features\", \
+         \"x\"]\nprograms = [\"alpha\"]\nno_idl = true\ncargo_test_args = [\"--features\", \
+         \"y\"]\n",
+    )
+    .unwrap();
+    let loaded = load_coverage_config(root).unwrap();
+    assert_eq!(
+        ["cargo", "test"],
+        loaded.config.native_test_command.as_slice()
+    );
+    assert_eq!(["--features", "x"], loaded.config.build_sbf_args.as_slice());
+    assert_eq!(["alpha"], loaded.config.programs.as_slice());
+    assert!(loaded.config.no_idl);
+    assert_eq!(
+        ["--features", "y"],
+        loaded.config.cargo_test_args.as_slice()
+    );
+
+    // smoelius: Arguments after `--` replace the configured `cargo test` arguments.
+    assert!(
+        anchor_coverage_stdout(root, &["cargo-test", "--print-config"])
+            .contains("cargo_test_args = [\"--features\", \"y\"]\n")
+    );
+    assert!(
+        anchor_coverage_stdout(root, &["cargo-test", "--print-config", "--", "--lib"])
+            .contains("cargo_test_args = [\"--lib\"]\n")
+    );
+}
+
+/// Runs `anchor-coverage` with `args` in `dir`, and returns its standard output
+fn anchor_coverage_stdout(dir: &Path, args: &[&str]) -> String {
+    let mut command = Command::new("cargo");
+    command.args([
+        "run",
+        "--bin=anchor-coverage",
+        "--manifest-path",
+        env!("CARGO_MANIFEST_PATH"),
+        "--quiet",
+        "--",
+    ]);
+    command.args(args);
+    command.current_dir(dir);
+    let output = command.output().unwrap();
+    assert!(output.status.success(), "command failed: {command:?}");
+    String::from_utf8(output.stdout).unwrap()
+}
+
 fn prepare_for_testing(dir: &str) -> Result<MutexGuard<'_, ()>> {
     download_patched_agave_tools(dir)?;
 
@@ -224,12 +1380,12 @@ fn download_patched_agave_tools(dir: impl AsRef<Path>) -> Result<()> {
         return Ok(());
     }
 
-    let filename = format!("patched-agave-tools-{}-{}.tar.gz", *AGAVE_TAG, OS);
+    let agave_tag = AGAVE_TAG.trim_end();
+    let filename = format!("patched-agave-tools-{agave_tag}-{OS}.tar.gz");
 
     let mut command = Command::new("wget");
     command.arg(format!(
-        "{SBPF_COVERAGE_DOWNLOAD_URL}/{}/{}",
-        *AGAVE_TAG, filename
+        "{SBPF_COVERAGE_DOWNLOAD_URL}/{agave_tag}/{filename}"
     ));
     command.current_dir(&dir);
     let status = command.status()?;
@@ -276,6 +1432,7 @@ fn anchor_coverage_command(dir: impl AsRef<Path>) -> Command {
         env!("CARGO_MANIFEST_PATH"),
         "--quiet",
         "--",
+        "--clean",
     ]);
     command.current_dir(dir);
     command
@@ -0,0 +1,79 @@
+//! Deletes what `anchor-coverage` writes to a trace directory, i.e., the effect of `--clean` and
+//! the `reset` subcommand.
+//!
+//! Only files `anchor-coverage` or the patched validator write are deleted, along with any
+//! directories left empty, e.g., `run-N` and test configuration directories. Other files are left
+//! in place, so a misconfigured trace directory costs at most coverage data.
+
+use crate::{
+    report::REPORT_FILENAME,
+    test_configs::{ANCHOR_TEST_LOG_FILENAME, COMPARISON_FILENAME},
+    test_names::TESTS_LOG_FILENAME,
+};
+use anyhow::{ensure, Result};
+use std::{
+    env::{current_dir, home_dir},
+    ffi::OsStr,
+    fs::{canonicalize, read_dir, remove_dir, remove_file},
+    path::Path,
+};
+
+/// Extensions of the files written for each trace
+const TRACE_EXTENSIONS: &[&str] = &["pcs", "insns", "lcov", "closest_match"];
+
+/// Names of the files written to the top of a trace directory or a test configuration's directory
+const FILENAMES: &[&str] = &[
+    REPORT_FILENAME,
+    TESTS_LOG_FILENAME,
+    COMPARISON_FILENAME,
+    ANCHOR_TEST_LOG_FILENAME,
+];
+
+/// Deletes the files `anchor-coverage` wrote to `trace_dir`, and then any directories left empty,
+/// including `trace_dir` itself
+///
+/// Fails if `trace_dir` is the workspace root `root`, the current directory, the home directory,
+/// or an ancestor of one of them, before deleting anything.
+pub fn clean(trace_dir: &Path, root: &Path) -> Result<()> {
+    if !trace_dir.try_exists()? {
+        return Ok(());
+    }
+    let trace_dir = canonicalize(trace_dir)?;
+    let protected = [Some(root.to_path_buf()), current_dir().ok(), home_dir()];
+    for dir in protected.into_iter().flatten() {
+        let dir = canonicalize(&dir).unwrap_or(dir);
+        ensure!(
+            !dir.starts_with(&trace_dir),
+            "refusing to clean trace directory `{}`, which contains `{}`",
+            trace_dir.display(),
+            dir.display()
+        );
+    }
+    clean_dir(&trace_dir)
+}
+
+fn clean_dir(dir: &Path) -> Result<()> {
+    for result in read_dir(dir)? {
+        let entry = result?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            clean_dir(&path)?;
+        } else if is_written_by_anchor_coverage(&path) {
+            remove_file(&path)?;
+        }
+    }
+    if read_dir(dir)?.next().is_none() {
+        remove_dir(dir)?;
+    }
+    Ok(())
+}
+
+fn is_written_by_anchor_coverage(path: &Path) -> bool {
+    path.extension()
+        .and_then(OsStr::to_str)
+        .is_some_and(|extension| TRACE_EXTENSIONS.contains(&extension))
+        || path
+            .file_name()
+            .and_then(OsStr::to_str)
+            .is_some_and(|file_name| FILENAMES.contains(&file_name))
+}
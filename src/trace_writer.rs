@@ -0,0 +1,122 @@
+//! Writes program counters files, so that in-process sBPF VMs (e.g., those of `solana-program-test`
+//! or `mollusk-svm`) can produce traces without a patched validator.
+//!
+//! A trace is a pair of files with a common stem: a `.pcs` file holding each executed instruction's
+//! program counter, and a `.insns` file holding the instruction itself. Both hold little-endian
+//! `u64`s. A program counter is an index into the program's text section, i.e., the instruction's
+//! offset divided by 8.
+//!
+//! A VM's tracing callback can pass each instruction to [`TraceWriter::record`], or, if the VM
+//! keeps a register trace, the whole trace can be passed to [`record_register_trace`].
+
+use anyhow::{Context, Result};
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::{
+    env::var_os,
+    fs::{remove_file, File, OpenOptions},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// The environment variable naming the directory to which traces are written
+pub const SBF_TRACE_DIR: &str = "SBF_TRACE_DIR";
+
+/// The index of the program counter in a register trace entry
+pub const PC_REGISTER: usize = 11;
+
+static NEXT_TRACE: AtomicUsize = AtomicUsize::new(0);
+
+/// Writes one trace, i.e., one program invocation's `.pcs` and `.insns` files
+pub struct TraceWriter {
+    pcs_path: PathBuf,
+    pcs_file: BufWriter<File>,
+    insns_file: BufWriter<File>,
+    len: usize,
+}
+
+impl TraceWriter {
+    /// Creates a trace in `sbf_trace_dir`, named uniquely for this process
+    pub fn create(sbf_trace_dir: &Path) -> Result<Self> {
+        let index = NEXT_TRACE.fetch_add(1, Ordering::SeqCst);
+        let pcs_path = sbf_trace_dir
+            .join(format!("{}-{index}", process::id()))
+            .with_extension("pcs");
+        let pcs_file = create_new(&pcs_path)?;
+        let insns_file = create_new(&pcs_path.with_extension("insns"))?;
+        Ok(Self {
+            pcs_path,
+            pcs_file: BufWriter::new(pcs_file),
+            insns_file: BufWriter::new(insns_file),
+            len: 0,
+        })
+    }
+
+    /// Creates a trace in the directory named by `SBF_TRACE_DIR`, if it is set
+    pub fn from_env() -> Result<Option<Self>> {
+        var_os(SBF_TRACE_DIR)
+            .map(|sbf_trace_dir| Self::create(Path::new(&sbf_trace_dir)))
+            .transpose()
+    }
+
+    /// Returns the path of the trace's `.pcs` file
+    #[must_use]
+    pub fn pcs_path(&self) -> &Path {
+        &self.pcs_path
+    }
+
+    /// Records the execution of instruction `insn` at program counter `pc`
+    ///
+    /// `insn` is the instruction's eight bytes as they appear in the program's text section, read
+    /// as a little-endian `u64`.
+    pub fn record(&mut self, pc: u64, insn: u64) -> Result<()> {
+        self.pcs_file.write_u64::<LittleEndian>(pc)?;
+        self.insns_file.write_u64::<LittleEndian>(insn)?;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Flushes the trace's files and returns the path of its `.pcs` file
+    ///
+    /// If no instructions were recorded, the files are removed and `None` is returned.
+    pub fn finish(mut self) -> Result<Option<PathBuf>> {
+        self.pcs_file.flush()?;
+        self.insns_file.flush()?;
+        if self.len == 0 {
+            remove_file(&self.pcs_path)?;
+            remove_file(self.pcs_path.with_extension("insns"))?;
+            return Ok(None);
+        }
+        Ok(Some(self.pcs_path))
+    }
+}
+
+/// Records each entry of a VM's register trace, looking up the executed instructions in `text`
+///
+/// Each entry holds the VM's registers, with the program counter at index [`PC_REGISTER`], as in
+/// `solana-sbpf`'s register traces. `text` is the program's text section.
+pub fn record_register_trace(
+    trace_writer: &mut TraceWriter,
+    register_trace: &[[u64; 12]],
+    text: &[u8],
+) -> Result<()> {
+    for registers in register_trace {
+        let pc = registers[PC_REGISTER];
+        let insn = usize::try_from(pc)
+            .ok()
+            .and_then(|pc| pc.checked_mul(8))
+            .and_then(|offset| text.get(offset..offset.checked_add(8)?))
+            .with_context(|| format!("program counter {pc} is outside the text section"))?;
+        trace_writer.record(pc, u64::from_le_bytes(insn.try_into().unwrap()))?;
+    }
+    Ok(())
+}
+
+fn create_new(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .with_context(|| format!("failed to create `{}`", path.display()))
+}
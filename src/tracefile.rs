@@ -0,0 +1,242 @@
+//! A parser and writer for lcov tracefiles.
+//!
+//! The parser accepts the records that `geninfo` and `cargo llvm-cov` produce: `TN`, `SF`, `FN`,
+//! `FNDA`, `BRDA`, `DA`, and `end_of_record`. Both the lcov 1.x form of `FN` (`FN:<line>,<name>`)
+//! and the lcov 2.x form (`FN:<start>,<end>,<name>`) are accepted, and a function is written back
+//! in the form it was read. Summary records (`FNF`, `FNH`, `BRF`, `BRH`, `LF`, and `LH`) are
+//! recomputed when writing, and unrecognized records are ignored.
+
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display, Formatter},
+    fs::read_to_string,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Default)]
+pub struct Tracefile {
+    /// Records keyed by test name (empty if none) and source file
+    pub records: BTreeMap<(String, PathBuf), Record>,
+}
+
+#[derive(Debug, Default)]
+pub struct Record {
+    /// Hits per line
+    pub lines: BTreeMap<u32, usize>,
+    /// Lines and hits per function name
+    pub functions: BTreeMap<String, Function>,
+    /// Times taken per line, block, and branch, or `None` if the branch's block was never executed
+    pub branches: BTreeMap<(u32, String, String), Option<usize>>,
+}
+
+#[derive(Debug, Default)]
+pub struct Function {
+    /// Start line, or `None` if the function has no `FN` record
+    pub line: Option<u32>,
+    /// End line, if the function's `FN` record has the lcov 2.x form
+    pub end_line: Option<u32>,
+    pub hits: usize,
+}
+
+impl Tracefile {
+    pub fn read(path: &Path) -> Result<Self> {
+        let contents = read_to_string(path)?;
+        contents
+            .parse()
+            .with_context(|| format!("failed to parse lcov file: {}", path.display()))
+    }
+
+    /// Adds `other`'s hit counts to `self`'s
+    pub fn merge(&mut self, other: Self) {
+        for (key, other_record) in other.records {
+            self.records.entry(key).or_default().merge(other_record);
+        }
+    }
+
+    /// Applies `remap` to each record's source file, merging records that become the same
+    #[must_use]
+    pub fn remap(self, remap: impl Fn(&Path) -> PathBuf) -> Self {
+        let mut remapped = Self::default();
+        for ((test_name, source_file), record) in self.records {
+            remapped
+                .records
+                .entry((test_name, remap(&source_file)))
+                .or_default()
+                .merge(record);
+        }
+        remapped
+    }
+
+    /// Returns the hits per line of each source file, summed over all tests
+    #[must_use]
+    pub fn line_hits(&self) -> BTreeMap<PathBuf, BTreeMap<u32, usize>> {
+        let mut line_hits = BTreeMap::<PathBuf, BTreeMap<u32, usize>>::new();
+        for ((_, source_file), record) in &self.records {
+            let hits = line_hits.entry(source_file.clone()).or_default();
+            for (&line, &count) in &record.lines {
+                *hits.entry(line).or_default() += count;
+            }
+        }
+        line_hits
+    }
+}
+
+impl Record {
+    fn merge(&mut self, other: Self) {
+        for (line, count) in other.lines {
+            *self.lines.entry(line).or_default() += count;
+        }
+        for (name, other_function) in other.functions {
+            let function = self.functions.entry(name).or_default();
+            function.line = function.line.or(other_function.line);
+            function.end_line = function.end_line.or(other_function.end_line);
+            function.hits += other_function.hits;
+        }
+        for (key, taken) in other.branches {
+            let branch = self.branches.entry(key).or_default();
+            *branch = match (*branch, taken) {
+                (Some(left), Some(right)) => Some(left + right),
+                (left, right) => left.or(right),
+            };
+        }
+    }
+}
+
+impl std::str::FromStr for Tracefile {
+    type Err = anyhow::Error;
+
+    fn from_str(contents: &str) -> Result<Self> {
+        let mut tracefile = Self::default();
+        let mut test_name = String::new();
+        let mut current = None::<(PathBuf, Record)>;
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line == "end_of_record" {
+                if let Some((source_file, record)) = current.take() {
+                    tracefile
+                        .records
+                        .entry((test_name.clone(), source_file))
+                        .or_default()
+                        .merge(record);
+                }
+                continue;
+            }
+            let Some((kind, value)) = line.split_once(':') else {
+                bail!("line {} is malformed: {line:?}", index + 1);
+            };
+            if kind == "TN" {
+                value.clone_into(&mut test_name);
+                continue;
+            }
+            if kind == "SF" {
+                current = Some((PathBuf::from(value), Record::default()));
+                continue;
+            }
+            let Some((_, record)) = &mut current else {
+                // smoelius: Ignore records outside of an `SF` ... `end_of_record` block.
+                continue;
+            };
+            parse_line(record, kind, value)
+                .with_context(|| format!("line {} is malformed: {line:?}", index + 1))?;
+        }
+        Ok(tracefile)
+    }
+}
+
+fn parse_line(record: &mut Record, kind: &str, value: &str) -> Result<()> {
+    let fields = value.split(',').collect::<Vec<_>>();
+    match (kind, fields.as_slice()) {
+        ("DA", [line, count, ..]) => {
+            *record.lines.entry(line.parse()?).or_default() += count.parse::<usize>()?;
+        }
+        // smoelius: lcov 2.x writes `FN:<start>,<end>,<name>`. A function name may contain commas,
+        // but cannot begin with a digit, so a numeric second field must be an end line.
+        ("FN", [line, end_line, name @ ..])
+            if !name.is_empty() && end_line.bytes().all(|b| b.is_ascii_digit()) =>
+        {
+            let function = record.functions.entry(name.join(",")).or_default();
+            function.line = Some(line.parse()?);
+            function.end_line = Some(end_line.parse()?);
+        }
+        ("FN", [line, name @ ..]) => {
+            let function = record.functions.entry(name.join(",")).or_default();
+            function.line = Some(line.parse()?);
+        }
+        ("FNDA", [count, name @ ..]) => {
+            let function = record.functions.entry(name.join(",")).or_default();
+            function.hits += count.parse::<usize>()?;
+        }
+        ("BRDA", [line, block, branch, taken]) => {
+            let taken = if *taken == "-" {
+                None
+            } else {
+                Some(taken.parse::<usize>()?)
+            };
+            let key = (line.parse()?, (*block).to_owned(), (*branch).to_owned());
+            let entry = record.branches.entry(key).or_default();
+            *entry = match (*entry, taken) {
+                (Some(left), Some(right)) => Some(left + right),
+                (left, right) => left.or(right),
+            };
+        }
+        ("DA" | "FN" | "FNDA" | "BRDA", _) => bail!("wrong number of fields"),
+        _ => {}
+    }
+    Ok(())
+}
+
+impl Display for Tracefile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for ((test_name, source_file), record) in &self.records {
+            if !test_name.is_empty() {
+                writeln!(f, "TN:{test_name}")?;
+            }
+            writeln!(f, "SF:{}", source_file.display())?;
+            for (name, function) in &record.functions {
+                match (function.line, function.end_line) {
+                    (Some(line), Some(end_line)) => writeln!(f, "FN:{line},{end_line},{name}")?,
+                    (Some(line), None) => writeln!(f, "FN:{line},{name}")?,
+                    (None, _) => {}
+                }
+            }
+            for (name, function) in &record.functions {
+                writeln!(f, "FNDA:{},{name}", function.hits)?;
+            }
+            if !record.functions.is_empty() {
+                writeln!(f, "FNF:{}", record.functions.len())?;
+                writeln!(
+                    f,
+                    "FNH:{}",
+                    count_hit(record.functions.values().map(|function| function.hits))
+                )?;
+            }
+            for ((line, block, branch), taken) in &record.branches {
+                let taken = taken.map_or_else(|| String::from("-"), |taken| taken.to_string());
+                writeln!(f, "BRDA:{line},{block},{branch},{taken}")?;
+            }
+            if !record.branches.is_empty() {
+                writeln!(f, "BRF:{}", record.branches.len())?;
+                writeln!(
+                    f,
+                    "BRH:{}",
+                    count_hit(record.branches.values().map(|taken| taken.unwrap_or(0)))
+                )?;
+            }
+            for (line, count) in &record.lines {
+                writeln!(f, "DA:{line},{count}")?;
+            }
+            writeln!(f, "LF:{}", record.lines.len())?;
+            writeln!(f, "LH:{}", count_hit(record.lines.values().copied()))?;
+            writeln!(f, "end_of_record")?;
+        }
+        Ok(())
+    }
+}
+
+fn count_hit(counts: impl Iterator<Item = usize>) -> usize {
+    counts.filter(|&count| count > 0).count()
+}
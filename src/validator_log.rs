@@ -0,0 +1,235 @@
+//! Correlates program counters files with the program invocations in a validator log.
+//!
+//! `solana-test-validator` logs each program invocation, the compute units it consumed, and whether
+//! it succeeded, e.g.:
+//!
+//! ```text
+//! [... DEBUG solana_runtime::message_processor::stable_log] Program Fg6P... invoke [1]
+//! [... DEBUG solana_runtime::message_processor::stable_log] Program Fg6P... consumed 1234 of 200000 compute units
+//! [... DEBUG solana_runtime::message_processor::stable_log] Program Fg6P... success
+//! ```
+//!
+//! The patched validator writes one program counters file per program execution. So the `n`th
+//! program counters file (ordered by modification time) produced by a program is assumed to
+//! correspond to the `n`th invocation of that program in the log.
+//!
+//! The log does not delimit transactions. But if the log contains transaction signatures (e.g.,
+//! because of the validator's `RUST_LOG` setting), each invocation is attributed to the most
+//! recent signature, and top-level invocations are numbered from that signature.
+
+use crate::{report::InvocationReport, Trace};
+use anyhow::Result;
+use std::{
+    collections::BTreeMap,
+    fs::{metadata, read_to_string, rename},
+    path::{Path, PathBuf},
+};
+use toml::{Table, Value};
+
+pub const VALIDATOR_LOG_PATH: &str = ".anchor/test-ledger/validator.log";
+
+/// The name of the log `solana-test-validator` writes to its ledger directory
+pub const VALIDATOR_LOG_FILENAME: &str = "validator.log";
+
+const BASE58_ALPHABET: &str = "123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+/// Returns the invocations in `contents`, in the order in which they began
+pub(crate) fn parse_validator_log(contents: &str) -> Vec<InvocationReport> {
+    let mut invocations = Vec::<InvocationReport>::new();
+    let mut stack = Vec::<usize>::new();
+    let mut signature = None::<String>;
+    let mut instruction_index = 0;
+
+    for line in contents.lines() {
+        // smoelius: Strip the `[<timestamp> <level> <target>]` prefix, if any.
+        let message = line
+            .strip_prefix('[')
+            .and_then(|rest| rest.split_once("] "))
+            .map_or(line, |(_, message)| message);
+
+        let Some(rest) = message.strip_prefix("Program ") else {
+            if let Some(found) = find_signature(message) {
+                signature = Some(found.to_owned());
+                instruction_index = 0;
+            }
+            continue;
+        };
+
+        let Some((program_id, event)) = rest.split_once(' ') else {
+            continue;
+        };
+
+        if let Some(stack_height) = event
+            .strip_prefix("invoke [")
+            .and_then(|rest| rest.strip_suffix(']'))
+            .and_then(|stack_height| stack_height.parse::<usize>().ok())
+        {
+            let top_level = stack_height == 1;
+            invocations.push(InvocationReport {
+                program_id: program_id.to_owned(),
+                signature: signature.clone(),
+                instruction_index: (top_level && signature.is_some()).then_some(instruction_index),
+                stack_height,
+                compute_units: None,
+                success: None,
+                error: None,
+            });
+            if top_level {
+                instruction_index += 1;
+            }
+            stack.push(invocations.len() - 1);
+            continue;
+        }
+
+        let Some(&top) = stack.last() else {
+            continue;
+        };
+        let invocation = &mut invocations[top];
+        if invocation.program_id != program_id {
+            continue;
+        }
+
+        if let Some(compute_units) = event
+            .strip_prefix("consumed ")
+            .and_then(|rest| rest.split_once(' '))
+            .and_then(|(compute_units, _)| compute_units.parse::<u64>().ok())
+        {
+            invocation.compute_units = Some(compute_units);
+        } else if event == "success" {
+            invocation.success = Some(true);
+            stack.pop();
+        } else if let Some(error) = event.strip_prefix("failed: ") {
+            invocation.success = Some(false);
+            invocation.error = Some(error.to_owned());
+            stack.pop();
+        }
+    }
+
+    invocations
+}
+
+/// Returns the first word in `message` that looks like a base58-encoded transaction signature
+fn find_signature(message: &str) -> Option<&str> {
+    message
+        .split(|c: char| !BASE58_ALPHABET.contains(c))
+        .find(|word| (86..=88).contains(&word.len()))
+}
+
+/// Reads the validator log at `path` and groups its invocations by program ID
+pub(crate) fn read_invocations(path: &Path) -> Result<BTreeMap<String, Vec<InvocationReport>>> {
+    let contents = read_to_string(path)?;
+    let mut invocations_by_program_id = BTreeMap::<String, Vec<InvocationReport>>::new();
+    for invocation in parse_validator_log(&contents) {
+        invocations_by_program_id
+            .entry(invocation.program_id.clone())
+            .or_default()
+            .push(invocation);
+    }
+    Ok(invocations_by_program_id)
+}
+
+/// Returns a map from program names to program IDs, according to `Anchor.toml`'s
+/// `[programs.localnet]` table
+pub(crate) fn program_ids(anchor_toml: &Path) -> Result<BTreeMap<String, String>> {
+    if !anchor_toml.try_exists()? {
+        return Ok(BTreeMap::new());
+    }
+    let contents = read_to_string(anchor_toml)?;
+    let table = contents.parse::<Table>()?;
+    Ok(table
+        .get("programs")
+        .and_then(Value::as_table)
+        .and_then(|table| table.get("localnet"))
+        .and_then(Value::as_table)
+        .into_iter()
+        .flatten()
+        .filter_map(|(name, value)| {
+            // smoelius: A program's entry may be a string or a table with an `address` key.
+            let program_id = value.as_str().or_else(|| {
+                value
+                    .as_table()
+                    .and_then(|table| table.get("address"))
+                    .and_then(Value::as_str)
+            })?;
+            Some((name.clone(), program_id.to_owned()))
+        })
+        .collect())
+}
+
+/// Labels `traces` with their invocations in the validator log at `validator_log_path`, and renames
+/// their lcov files to reflect the labels
+///
+/// If the number of a program's invocations in the log differs from the number of its traces, its
+/// traces are not labeled.
+pub(crate) fn label_traces(
+    traces: &mut [Trace<'_>],
+    validator_log_path: &Path,
+    anchor_toml: &Path,
+) -> Result<()> {
+    let mut invocations_by_program_id = read_invocations(validator_log_path)?;
+    let program_ids = program_ids(anchor_toml)?;
+
+    let mut traces_by_program_name = BTreeMap::<String, Vec<&mut Trace<'_>>>::new();
+    for trace in traces.iter_mut() {
+        traces_by_program_name
+            .entry(trace.dwarf.program_name())
+            .or_default()
+            .push(trace);
+    }
+
+    for (program_name, mut traces) in traces_by_program_name {
+        let Some(program_id) = program_ids.get(&program_name) else {
+            eprintln!("Warning: Could not find program ID for `{program_name}` in Anchor.toml");
+            continue;
+        };
+        let invocations = invocations_by_program_id
+            .remove(program_id)
+            .unwrap_or_default();
+        if invocations.len() != traces.len() {
+            eprintln!(
+                "Warning: Found {} invocations of `{program_name}` in validator log but {} \
+                 program counters files; not labeling `{program_name}`'s traces",
+                invocations.len(),
+                traces.len()
+            );
+            continue;
+        }
+        let mut keyed_traces = traces
+            .drain(..)
+            .map(|trace| Ok((metadata(&trace.pcs_path)?.modified()?, trace)))
+            .collect::<Result<Vec<_>>>()?;
+        keyed_traces.sort_by(|(left_modified, left), (right_modified, right)| {
+            left_modified
+                .cmp(right_modified)
+                .then_with(|| left.pcs_path.cmp(&right.pcs_path))
+        });
+        for ((_, trace), invocation) in keyed_traces.into_iter().zip(invocations) {
+            let lcov_path = labeled_lcov_path(&trace.lcov_path, &invocation);
+            rename(&trace.lcov_path, &lcov_path)?;
+            trace.lcov_path = lcov_path;
+            trace.invocation = Some(invocation);
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns `lcov_path` with the invocation's signature prefix and outcome inserted before the
+/// extension, e.g., `1234.5VERv8NM.success.lcov`
+fn labeled_lcov_path(lcov_path: &Path, invocation: &InvocationReport) -> PathBuf {
+    let mut file_name = lcov_path
+        .file_stem()
+        .map(|file_stem| file_stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    if let Some(signature) = &invocation.signature {
+        file_name.push('.');
+        file_name.extend(signature.chars().take(8));
+    }
+    match invocation.success {
+        Some(true) => file_name.push_str(".success"),
+        Some(false) => file_name.push_str(".failure"),
+        None => {}
+    }
+    file_name.push_str(".lcov");
+    lcov_path.with_file_name(file_name)
+}